@@ -0,0 +1,159 @@
+use super::inflight::Inflight;
+use super::protocol::{PublishPacket, QualityOfService};
+use super::topic::TopicFilter;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+use std::u16;
+
+// Everything about a client which outlives its connection when it connects with clean session set to 0
+pub struct ClientState {
+	// The filters the client is subscribed to, with the QoS it was granted
+	pub subscriptions: HashMap<TopicFilter, QualityOfService>,
+	// Messages sent to the client which it hasn't acknowledged yet
+	pub inflight: Inflight,
+	// Packet ids of QoS 2 messages from the client which have been routed, but not released yet
+	pub awaiting_release: HashSet<u16>,
+	// Messages published while the client was disconnected, with the QoS its subscription was granted.
+	// RETAIN is already set to what the client should receive.
+	pub queued: VecDeque<(PublishPacket, QualityOfService)>,
+	// When the state is thrown away if the client hasn't reconnected, set when it's saved
+	pub expires_at: Option<Instant>,
+	// The packet id for the next QoS 1 or 2 message sent to the client
+	next_packet_id: u16
+}
+
+impl ClientState {
+	pub fn new() -> ClientState {
+		ClientState {
+			subscriptions: HashMap::new(),
+			inflight: Inflight::new(),
+			awaiting_release: HashSet::new(),
+			queued: VecDeque::new(),
+			expires_at: None,
+			next_packet_id: 1
+		}
+	}
+
+	// Packet ids only need to be unique among the messages still waiting to be acknowledged,
+	// so they wrap around, skipping any still in use. 0 isn't a valid packet id. Inflight is
+	// indexed by packet id, so even with almost every id in use this is one pass at most.
+	pub fn take_packet_id(&mut self) -> Option<u16> {
+		if self.inflight.len() >= u16::MAX as usize {
+			return None;
+		}
+
+		loop {
+			let packet_id = self.next_packet_id;
+
+			self.next_packet_id = match self.next_packet_id {
+				u16::MAX => 1,
+				n => n + 1
+			};
+
+			if !self.inflight.contains(packet_id) {
+				return Some(packet_id);
+			}
+		}
+	}
+
+	// Keeps a message for the client to receive when it reconnects. Only QoS 1 and 2 messages are kept,
+	// and once max_queued messages are waiting any more are dropped. Returns false if the queue is full.
+	pub fn queue(&mut self, publish: &PublishPacket, granted_qos: QualityOfService, retain: bool, max_queued: usize) -> bool {
+		if publish.qos == QualityOfService::AtMostOnce || granted_qos == QualityOfService::AtMostOnce {
+			return true;
+		}
+
+		if self.queued.len() >= max_queued {
+			return false;
+		}
+
+		self.queued.push_back((PublishPacket { retain: retain, ..publish.clone() }, granted_qos));
+		true
+	}
+}
+
+#[cfg(test)]
+use super::topic::TopicName;
+
+#[cfg(test)]
+fn publish(packet_id: Option<u16>, qos: QualityOfService) -> PublishPacket {
+	PublishPacket {
+		dup: false,
+		qos: qos,
+		retain: false,
+		topic_name: TopicName::new("a/b").unwrap(),
+		packet_id: packet_id,
+		properties: Vec::new(),
+		payload: Vec::new()
+	}
+}
+
+#[test]
+fn test_take_packet_id() {
+	let mut client_state = ClientState::new();
+
+	assert_eq!(client_state.take_packet_id(), Some(1));
+
+	// Packet ids still inflight are skipped
+	client_state.inflight.insert(publish(Some(3), QualityOfService::AtLeastOnce));
+	assert_eq!(client_state.take_packet_id(), Some(2));
+	assert_eq!(client_state.take_packet_id(), Some(4));
+
+	// 0 is skipped when wrapping around
+	client_state.next_packet_id = u16::MAX;
+	assert_eq!(client_state.take_packet_id(), Some(u16::MAX));
+	assert_eq!(client_state.take_packet_id(), Some(1));
+}
+
+#[test]
+fn test_queue() {
+	let mut client_state = ClientState::new();
+
+	assert!(client_state.queue(&publish(None, QualityOfService::AtMostOnce), QualityOfService::ExactlyOnce, false, 10));
+	assert!(client_state.queue(&publish(Some(1), QualityOfService::AtLeastOnce), QualityOfService::AtMostOnce, false, 10));
+	assert!(client_state.queued.is_empty());
+
+	assert!(client_state.queue(&publish(Some(1), QualityOfService::ExactlyOnce), QualityOfService::AtLeastOnce, false, 10));
+	assert_eq!(client_state.queued, vec!((publish(Some(1), QualityOfService::ExactlyOnce), QualityOfService::AtLeastOnce)));
+
+	// RETAIN is kept the way the client should receive it
+	assert!(client_state.queue(&publish(Some(2), QualityOfService::AtLeastOnce), QualityOfService::AtLeastOnce, true, 10));
+	assert!(client_state.queued[1].0.retain);
+}
+
+#[test]
+fn test_queue_full() {
+	let mut client_state = ClientState::new();
+
+	assert!(client_state.queue(&publish(Some(1), QualityOfService::AtLeastOnce), QualityOfService::AtLeastOnce, false, 2));
+	assert!(client_state.queue(&publish(Some(2), QualityOfService::AtLeastOnce), QualityOfService::AtLeastOnce, false, 2));
+
+	// The newest message is the one dropped
+	assert!(!client_state.queue(&publish(Some(3), QualityOfService::AtLeastOnce), QualityOfService::AtLeastOnce, false, 2));
+	assert_eq!(client_state.queued.iter().map(|&(ref publish, _)| publish.packet_id).collect::<Vec<_>>(), vec!(Some(1), Some(2)));
+
+	// QoS 0 messages are never kept, so they don't count as dropped
+	assert!(client_state.queue(&publish(None, QualityOfService::AtMostOnce), QualityOfService::AtLeastOnce, false, 2));
+}
+
+#[test]
+fn test_take_packet_id_exhausted() {
+	let mut client_state = ClientState::new();
+
+	for packet_id in 2..u16::MAX {
+		client_state.inflight.insert(publish(Some(packet_id), QualityOfService::AtLeastOnce));
+	}
+
+	// Only 1 and 65535 are free
+	assert_eq!(client_state.take_packet_id(), Some(1));
+	client_state.inflight.insert(publish(Some(1), QualityOfService::AtLeastOnce));
+	assert_eq!(client_state.take_packet_id(), Some(u16::MAX));
+	client_state.inflight.insert(publish(Some(u16::MAX), QualityOfService::AtLeastOnce));
+
+	assert_eq!(client_state.take_packet_id(), None);
+
+	// Once one is acknowledged it's found again
+	client_state.inflight.acknowledge(40_000);
+	assert_eq!(client_state.take_packet_id(), Some(40_000));
+}
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+// Settings for the broker which are fixed for the lifetime of the server
+#[derive(Clone, Debug)]
+pub struct Config {
+	pub port: u16,
+	// The largest packet, including the fixed header, that a client may send.
+	// Anything bigger closes the connection before its body is buffered.
+	pub max_packet_size: usize,
+	// How many connections can be open at once. Any more are refused.
+	pub max_connections: usize,
+	// How long a new connection has to send CONNECT before it's closed
+	pub connect_timeout: Duration,
+	// How long the state of a disconnected client is kept when it asked for it to be kept. MQTT 5 clients can
+	// ask for less, and are told if they asked for more. Earlier versions always get this long.
+	pub session_expiry: Duration,
+	// The most messages kept for a disconnected client. Any more are dropped.
+	pub max_queued_messages: usize,
+	// The most bytes waiting to be written to a connection. A client which falls this far
+	// behind isn't reading, and its connection is closed.
+	pub max_write_queue_size: usize,
+	// Whether the will of a connection is published when another connection
+	// with the same client id takes over from it
+	pub will_on_takeover: bool
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			port: 1883,
+			max_packet_size: 1024 * 1024,
+			max_connections: 1024,
+			connect_timeout: Duration::from_secs(10),
+			session_expiry: Duration::from_secs(24 * 60 * 60),
+			max_queued_messages: 1000,
+			max_write_queue_size: 4 * 1024 * 1024,
+			will_on_takeover: true
+		}
+	}
+}
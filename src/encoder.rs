@@ -0,0 +1,809 @@
+use std::io;
+use std::io::ErrorKind;
+
+use protocol::{ConnectFlags, ConnectPacket, ConnectReturnCode, ControlPacketType, Packet, PublishPacket};
+use protocol::{Property, ProtocolVersion, QualityOfService, ReasonCode, RetainHandling, SubscribeReturnCode, SubscribeTopic};
+
+// The largest value the four byte remaining length encoding can hold
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+fn encode_control_type(control_type: ControlPacketType) -> u8 {
+	match control_type {
+		ControlPacketType::Connect => 1,
+		ControlPacketType::ConnectAck => 2,
+		ControlPacketType::Publish => 3,
+		ControlPacketType::PublishAck => 4,
+		ControlPacketType::PublishReceived => 5,
+		ControlPacketType::PublishRelease => 6,
+		ControlPacketType::PublishComplete => 7,
+		ControlPacketType::Subscribe => 8,
+		ControlPacketType::SubscribeAck => 9,
+		ControlPacketType::Unsubscribe => 10,
+		ControlPacketType::UnsubscribeAck => 11,
+		ControlPacketType::PingRequest => 12,
+		ControlPacketType::PingResponse => 13,
+		ControlPacketType::Disconnect => 14,
+		ControlPacketType::Auth => 15
+	}
+}
+
+// The low nibble of the first byte. PUBLISH carries its flags here, and
+// PUBREL, SUBSCRIBE and UNSUBSCRIBE have reserved bits which must be 0010.
+fn encode_fixed_header_flags(packet: &Packet) -> u8 {
+	match *packet {
+		Packet::Publish(ref publish) => {
+			let qos: u8 = publish.qos.into();
+			let mut flags = qos << 1;
+
+			if publish.dup { flags |= 0b1000; }
+			if publish.retain { flags |= 0b0001; }
+
+			flags
+		}
+		Packet::PublishRelease { .. } | Packet::Subscribe { .. } | Packet::Unsubscribe { .. } => 0b0010,
+		_ => 0b0000
+	}
+}
+
+// Also used for property lengths and subscription identifiers, which share the encoding
+pub fn encode_remaining_length(length: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+	if length > MAX_REMAINING_LENGTH {
+		return Err(io::Error::new(ErrorKind::InvalidInput, format!("remaining length {} is too large to encode", length)));
+	}
+
+	let mut value = length;
+
+	loop {
+		let mut encoded_byte = (value % 128) as u8;
+		value /= 128;
+
+		if value > 0 {
+			encoded_byte |= 0b10000000;
+		}
+
+		buf.push(encoded_byte);
+
+		if value == 0 {
+			break;
+		}
+	}
+
+	Ok(())
+}
+
+fn encode_u16(value: u16, buf: &mut Vec<u8>) {
+	buf.push((value >> 8) as u8);
+	buf.push(value as u8);
+}
+
+fn encode_byte_array(bytes: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+	if bytes.len() > u16::max_value() as usize {
+		return Err(io::Error::new(ErrorKind::InvalidInput, format!("length prefixed field of {} bytes is longer than 65535 bytes", bytes.len())));
+	}
+
+	encode_u16(bytes.len() as u16, buf);
+	buf.extend_from_slice(bytes);
+	Ok(())
+}
+
+fn encode_utf8(string: &str, buf: &mut Vec<u8>) -> io::Result<()> {
+	encode_byte_array(string.as_bytes(), buf)
+}
+
+fn encode_u32(value: u32, buf: &mut Vec<u8>) {
+	buf.push((value >> 24) as u8);
+	buf.push((value >> 16) as u8);
+	buf.push((value >> 8) as u8);
+	buf.push(value as u8);
+}
+
+fn encode_property(property: &Property, buf: &mut Vec<u8>) -> io::Result<()> {
+	buf.push(property.identifier().into());
+
+	match *property {
+		Property::PayloadFormatIndicator(value) |
+		Property::RequestProblemInformation(value) |
+		Property::RequestResponseInformation(value) |
+		Property::MaximumQualityOfService(value) |
+		Property::RetainAvailable(value) |
+		Property::WildcardSubscriptionAvailable(value) |
+		Property::SubscriptionIdentifierAvailable(value) |
+		Property::SharedSubscriptionAvailable(value) => buf.push(value),
+		Property::ServerKeepAlive(value) |
+		Property::ReceiveMaximum(value) |
+		Property::TopicAliasMaximum(value) |
+		Property::TopicAlias(value) => encode_u16(value, buf),
+		Property::MessageExpiryInterval(value) |
+		Property::SessionExpiryInterval(value) |
+		Property::WillDelayInterval(value) |
+		Property::MaximumPacketSize(value) => encode_u32(value, buf),
+		Property::SubscriptionIdentifier(value) => try!(encode_remaining_length(value as usize, buf)),
+		Property::ContentType(ref value) |
+		Property::ResponseTopic(ref value) |
+		Property::AssignedClientIdentifier(ref value) |
+		Property::AuthenticationMethod(ref value) |
+		Property::ResponseInformation(ref value) |
+		Property::ServerReference(ref value) |
+		Property::ReasonString(ref value) => try!(encode_utf8(value, buf)),
+		Property::CorrelationData(ref value) |
+		Property::AuthenticationData(ref value) => try!(encode_byte_array(value, buf)),
+		Property::UserProperty(ref key, ref value) => {
+			try!(encode_utf8(key, buf));
+			try!(encode_utf8(value, buf));
+		}
+	}
+
+	Ok(())
+}
+
+// The property block is prefixed with its length in bytes
+fn encode_properties(properties: &[Property], buf: &mut Vec<u8>) -> io::Result<()> {
+	let mut block = Vec::new();
+
+	for property in properties {
+		try!(encode_property(property, &mut block));
+	}
+
+	try!(encode_remaining_length(block.len(), buf));
+	buf.extend_from_slice(&block);
+	Ok(())
+}
+
+// Leaves off the end of the packet where the spec allows it, which is when the
+// reason code is Success and there are no properties
+fn encode_optional_reason_code_and_properties(reason_code: ReasonCode, properties: &[Property], buf: &mut Vec<u8>) -> io::Result<()> {
+	if reason_code == ReasonCode::Success && properties.is_empty() {
+		return Ok(());
+	}
+
+	buf.push(reason_code.into());
+
+	if !properties.is_empty() {
+		try!(encode_properties(properties, buf));
+	}
+
+	Ok(())
+}
+
+fn encode_connect_flags(connect_flags: &ConnectFlags) -> u8 {
+	let will_qos: u8 = connect_flags.will_qos.into();
+	let mut byte = will_qos << 3;
+
+	if connect_flags.username { byte |= 0b10000000; }
+	if connect_flags.password { byte |= 0b01000000; }
+	if connect_flags.will_retain { byte |= 0b00100000; }
+	if connect_flags.will_flag { byte |= 0b00000100; }
+	if connect_flags.clean_session { byte |= 0b00000010; }
+
+	byte
+}
+
+// CONNECT is encoded for the version it asks for, rather than the connection's version
+fn encode_connect(connect: &ConnectPacket, buf: &mut Vec<u8>) -> io::Result<()> {
+	let variable_header = &connect.variable_header;
+	let payload = &connect.payload;
+	let mqtt5 = variable_header.protocol_version() == Some(ProtocolVersion::Mqtt5);
+
+	try!(encode_utf8(&variable_header.protocol_name, buf));
+	buf.push(variable_header.protocol_level);
+	buf.push(encode_connect_flags(&variable_header.connect_flags));
+	encode_u16(variable_header.keep_alive, buf);
+
+	if mqtt5 {
+		try!(encode_properties(&variable_header.properties, buf));
+	}
+
+	try!(encode_utf8(&payload.client_id, buf));
+
+	if mqtt5 && variable_header.connect_flags.will_flag {
+		try!(encode_properties(&payload.will_properties, buf));
+	}
+
+	if let Some(ref will_topic) = payload.will_topic {
+		try!(encode_utf8(will_topic.as_str(), buf));
+	}
+
+	if let Some(ref will_message) = payload.will_message {
+		try!(encode_byte_array(will_message, buf));
+	}
+
+	if let Some(ref username) = payload.username {
+		try!(encode_utf8(username, buf));
+	}
+
+	if let Some(ref password) = payload.password {
+		try!(encode_byte_array(password, buf));
+	}
+
+	Ok(())
+}
+
+// QoS 1 and 2 messages must have a packet id and QoS 0 messages can't, since the receiver decides from the QoS whether to read one
+fn encode_publish(publish: &PublishPacket, version: ProtocolVersion, buf: &mut Vec<u8>) -> io::Result<()> {
+	try!(encode_utf8(publish.topic_name.as_str(), buf));
+
+	match (publish.qos, publish.packet_id) {
+		(QualityOfService::AtMostOnce, None) => {}
+		(QualityOfService::AtMostOnce, Some(_)) => {
+			return Err(io::Error::new(ErrorKind::InvalidInput, "QoS 0 PUBLISH packets can't have a packet id"));
+		}
+		(_, Some(packet_id)) => encode_u16(packet_id, buf),
+		(_, None) => {
+			return Err(io::Error::new(ErrorKind::InvalidInput, "QoS 1 and 2 PUBLISH packets need a packet id"));
+		}
+	}
+
+	if version.is_mqtt5() {
+		try!(encode_properties(&publish.properties, buf));
+	}
+
+	buf.extend_from_slice(&publish.payload);
+	Ok(())
+}
+
+// The subscription options byte. Before MQTT 5 only the QoS bits are used.
+fn encode_subscription_options(topic: &SubscribeTopic, version: ProtocolVersion) -> u8 {
+	let mut byte: u8 = topic.qos.into();
+
+	if version.is_mqtt5() {
+		if topic.no_local { byte |= 0b00000100; }
+		if topic.retain_as_published { byte |= 0b00001000; }
+
+		byte |= match topic.retain_handling {
+			RetainHandling::SendAtSubscribe => 0,
+			RetainHandling::SendAtNewSubscribe => 1,
+			RetainHandling::DoNotSend => 2
+		} << 4;
+	}
+
+	byte
+}
+
+// Everything after the fixed header. The MQTT 5 fields are left out for earlier versions.
+fn encode_body(packet: &Packet, version: ProtocolVersion, buf: &mut Vec<u8>) -> io::Result<()> {
+	let mqtt5 = version.is_mqtt5();
+
+	match *packet {
+		Packet::Connect(ref connect) => try!(encode_connect(connect, buf)),
+		Packet::ConnectAck { session_present, reason_code, ref properties } => {
+			// A 3.1 client resuming its session still gets 0
+			buf.push((session_present && version.has_session_present()) as u8);
+
+			if mqtt5 {
+				buf.push(reason_code.into());
+				try!(encode_properties(properties, buf));
+			} else {
+				buf.push(ConnectReturnCode::from(reason_code).into());
+			}
+		}
+		Packet::Publish(ref publish) => try!(encode_publish(publish, version, buf)),
+		Packet::PublishAck { packet_id, reason_code, ref properties } |
+		Packet::PublishReceived { packet_id, reason_code, ref properties } |
+		Packet::PublishRelease { packet_id, reason_code, ref properties } |
+		Packet::PublishComplete { packet_id, reason_code, ref properties } => {
+			encode_u16(packet_id, buf);
+
+			if mqtt5 {
+				try!(encode_optional_reason_code_and_properties(reason_code, properties, buf));
+			}
+		}
+		Packet::Subscribe { packet_id, ref properties, ref topics } => {
+			encode_u16(packet_id, buf);
+
+			if mqtt5 {
+				try!(encode_properties(properties, buf));
+			}
+
+			for topic in topics {
+				try!(encode_utf8(topic.topic_filter.as_str(), buf));
+				buf.push(encode_subscription_options(topic, version));
+			}
+		}
+		Packet::SubscribeAck { packet_id, ref properties, ref return_codes } => {
+			encode_u16(packet_id, buf);
+
+			if mqtt5 {
+				try!(encode_properties(properties, buf));
+			}
+
+			for return_code in return_codes {
+				match *return_code {
+					SubscribeReturnCode::Success(qos) => buf.push(qos.into()),
+					SubscribeReturnCode::Failure(reason_code) if mqtt5 => buf.push(reason_code.into()),
+					SubscribeReturnCode::Failure(_) => buf.push(0x80)
+				}
+			}
+		}
+		Packet::Unsubscribe { packet_id, ref properties, ref topic_filters } => {
+			encode_u16(packet_id, buf);
+
+			if mqtt5 {
+				try!(encode_properties(properties, buf));
+			}
+
+			for topic_filter in topic_filters {
+				try!(encode_utf8(topic_filter.as_str(), buf));
+			}
+		}
+		Packet::UnsubscribeAck { packet_id, ref properties, ref reason_codes } => {
+			encode_u16(packet_id, buf);
+
+			if mqtt5 {
+				try!(encode_properties(properties, buf));
+
+				for &reason_code in reason_codes {
+					buf.push(reason_code.into());
+				}
+			}
+		}
+		Packet::PingRequest | Packet::PingResponse => {}
+		Packet::Disconnect { reason_code, ref properties } => {
+			if mqtt5 {
+				try!(encode_optional_reason_code_and_properties(reason_code, properties, buf));
+			}
+		}
+		Packet::Auth { reason_code, ref properties } => {
+			if !mqtt5 {
+				return Err(io::Error::new(ErrorKind::InvalidInput, "AUTH packets only exist in MQTT 5"));
+			}
+
+			try!(encode_optional_reason_code_and_properties(reason_code, properties, buf));
+		}
+	}
+
+	Ok(())
+}
+
+// Serializes a whole control packet for a connection using the given version, appending it to buf.
+// Fails with InvalidInput, leaving buf as it was, if the packet doesn't fit the limits of the encoding.
+pub fn encode_packet(packet: &Packet, version: ProtocolVersion, buf: &mut Vec<u8>) -> io::Result<()> {
+	let mut body = Vec::new();
+	try!(encode_body(packet, version, &mut body));
+
+	let mut remaining_length = Vec::new();
+	try!(encode_remaining_length(body.len(), &mut remaining_length));
+
+	buf.push((encode_control_type(packet.control_type()) << 4) | encode_fixed_header_flags(packet));
+	buf.extend_from_slice(&remaining_length);
+	buf.extend_from_slice(&body);
+	Ok(())
+}
+
+#[cfg(test)]
+use nom::IResult;
+#[cfg(test)]
+use parser::*;
+#[cfg(test)]
+use protocol::*;
+#[cfg(test)]
+use topic::*;
+
+#[cfg(test)]
+fn assert_round_trip(packet: Packet, expected: Vec<u8>) {
+	assert_version_round_trip(packet, ProtocolVersion::Mqtt311, expected);
+}
+
+#[cfg(test)]
+fn assert_version_round_trip(packet: Packet, version: ProtocolVersion, expected: Vec<u8>) {
+	let mut buf = Vec::new();
+	encode_packet(&packet, version, &mut buf).unwrap();
+	assert_eq!(buf, expected);
+
+	match packet_parser(&buf, version) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, packet);
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_encode_remaining_length() {
+	let cases = vec!(
+		(0, vec!(0x00)),
+		(127, vec!(0x7F)),
+		(128, vec!(0x80, 0x01)),
+		(321, vec!(0xC1, 0x02)),
+		(16_383, vec!(0xFF, 0x7F)),
+		(16_384, vec!(0x80, 0x80, 0x01)),
+		(2_097_151, vec!(0xFF, 0xFF, 0x7F)),
+		(2_097_152, vec!(0x80, 0x80, 0x80, 0x01)),
+		(MAX_REMAINING_LENGTH, vec!(0xFF, 0xFF, 0xFF, 0x7F))
+	);
+
+	for (length, expected) in cases {
+		let mut buf = Vec::new();
+		encode_remaining_length(length, &mut buf).unwrap();
+		assert_eq!(buf, expected);
+
+		// The fixed header parser should read back the same length
+		let mut packet = vec!(0xC0);
+		packet.extend_from_slice(&buf);
+
+		match fixed_header_parser(&packet) {
+			IResult::Done(_, o) => assert_eq!(o.remaining_length as usize, length),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_encode_connect() {
+	let packet = Packet::Connect(ConnectPacket {
+		variable_header: ConnectVariableHeader {
+			protocol_name: "MQTT".into(),
+			protocol_level: 4,
+			connect_flags: ConnectFlags {
+				clean_session: true,
+				will_flag: true,
+				will_qos: QualityOfService::AtLeastOnce,
+				will_retain: true,
+				password: true,
+				username: true
+			},
+			keep_alive: 60,
+			properties: Vec::new()
+		},
+		payload: ConnectPayload {
+			client_id: "abc".into(),
+			will_properties: Vec::new(),
+			will_topic: Some(TopicName::new("will").unwrap()),
+			will_message: Some(vec!(0xDE, 0xAD)),
+			username: Some("user".into()),
+			password: Some(vec!(0x01, 0x02))
+		}
+	});
+
+	assert_round_trip(packet, vec!(
+		0x10, 0x23, // Fixed header
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x04, // Protocol Level
+		0b11101110, // Connect Flags
+		0x00, 0x3C, // Keep alive
+		0x00, 0x03, b'a', b'b', b'c', // Client ID
+		0x00, 0x04, b'w', b'i', b'l', b'l', // Will Topic
+		0x00, 0x02, 0xDE, 0xAD, // Will Message
+		0x00, 0x04, b'u', b's', b'e', b'r', // Username
+		0x00, 0x02, 0x01, 0x02 // Password
+	));
+}
+
+#[test]
+fn test_encode_connect_ack() {
+	assert_round_trip(Packet::ConnectAck { session_present: true, reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0x20, 0x02, 0x01, 0x00));
+	assert_round_trip(Packet::ConnectAck { session_present: false, reason_code: ReasonCode::UnsupportedProtocolVersion, properties: Vec::new() }, vec!(0x20, 0x02, 0x00, 0x01));
+}
+
+#[test]
+fn test_encode_connect_ack_mqtt31_resume() {
+	// A 3.1 client with a saved session is resumed like any other, but the flags byte is reserved
+	let mut buf = Vec::new();
+	encode_packet(&Packet::ConnectAck {
+		session_present: true,
+		reason_code: ReasonCode::Success,
+		properties: Vec::new()
+	}, ProtocolVersion::Mqtt31, &mut buf).unwrap();
+
+	assert_eq!(buf, vec!(0x20, 0x02, 0x00, 0x00));
+}
+
+#[test]
+fn test_encode_mqtt31_connect_and_connect_ack() {
+	let version = ProtocolVersion::Mqtt31;
+
+	let packet = Packet::Connect(ConnectPacket {
+		variable_header: ConnectVariableHeader {
+			protocol_name: "MQIsdp".into(),
+			protocol_level: 3,
+			connect_flags: ConnectFlags {
+				clean_session: false,
+				will_flag: false,
+				will_qos: QualityOfService::AtMostOnce,
+				will_retain: false,
+				password: false,
+				username: false
+			},
+			keep_alive: 30,
+			properties: Vec::new()
+		},
+		payload: ConnectPayload {
+			client_id: "c".into(),
+			will_properties: Vec::new(),
+			will_topic: None,
+			will_message: None,
+			username: None,
+			password: None
+		}
+	});
+
+	assert_version_round_trip(packet, version, vec!(
+		0x10, 0x0F, // Fixed header
+		0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', // Protocol Name
+		0x03, // Protocol Level
+		0x00, // Connect Flags
+		0x00, 0x1E, // Keep alive
+		0x00, 0x01, b'c' // Client ID
+	));
+
+	assert_version_round_trip(Packet::ConnectAck { session_present: false, reason_code: ReasonCode::Success, properties: Vec::new() }, version, vec!(0x20, 0x02, 0x00, 0x00));
+	assert_version_round_trip(Packet::ConnectAck { session_present: false, reason_code: ReasonCode::NotAuthorized, properties: Vec::new() }, version, vec!(0x20, 0x02, 0x00, 0x05));
+}
+
+#[test]
+fn test_encode_publish() {
+	let packet = Packet::Publish(PublishPacket {
+		dup: true,
+		qos: QualityOfService::ExactlyOnce,
+		retain: false,
+		topic_name: TopicName::new("a/b").unwrap(),
+		packet_id: Some(7),
+		properties: Vec::new(),
+		payload: vec!(1, 2, 3)
+	});
+
+	assert_round_trip(packet, vec!(0x3C, 0x0A, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x07, 1, 2, 3));
+
+	let packet = Packet::Publish(PublishPacket {
+		dup: false,
+		qos: QualityOfService::AtMostOnce,
+		retain: true,
+		topic_name: TopicName::new("a").unwrap(),
+		packet_id: None,
+		properties: Vec::new(),
+		payload: Vec::new()
+	});
+
+	assert_round_trip(packet, vec!(0x31, 0x03, 0x00, 0x01, b'a'));
+}
+
+#[test]
+fn test_encode_packet_id_packets() {
+	assert_round_trip(Packet::PublishAck { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0x40, 0x02, 0x00, 0x01));
+	assert_round_trip(Packet::PublishReceived { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0x50, 0x02, 0x00, 0x02));
+	assert_round_trip(Packet::PublishRelease { packet_id: 3, reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0x62, 0x02, 0x00, 0x03));
+	assert_round_trip(Packet::PublishComplete { packet_id: 4, reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0x70, 0x02, 0x00, 0x04));
+	assert_round_trip(Packet::UnsubscribeAck { packet_id: 5, properties: Vec::new(), reason_codes: Vec::new() }, vec!(0xB0, 0x02, 0x00, 0x05));
+}
+
+#[test]
+fn test_encode_subscribe() {
+	let packet = Packet::Subscribe {
+		packet_id: 10,
+		properties: Vec::new(),
+		topics: vec!(
+			SubscribeTopic::new(TopicFilter::new("a/+").unwrap(), QualityOfService::AtLeastOnce)
+		)
+	};
+
+	assert_round_trip(packet, vec!(0x82, 0x08, 0x00, 0x0A, 0x00, 0x03, b'a', b'/', b'+', 0x01));
+}
+
+#[test]
+fn test_encode_subscribe_ack() {
+	let packet = Packet::SubscribeAck {
+		packet_id: 10,
+		properties: Vec::new(),
+		return_codes: vec!(
+			SubscribeReturnCode::Success(QualityOfService::AtMostOnce),
+			SubscribeReturnCode::Success(QualityOfService::ExactlyOnce),
+			SubscribeReturnCode::Failure(ReasonCode::UnspecifiedError)
+		)
+	};
+
+	assert_round_trip(packet, vec!(0x90, 0x05, 0x00, 0x0A, 0x00, 0x02, 0x80));
+}
+
+#[test]
+fn test_encode_unsubscribe() {
+	let packet = Packet::Unsubscribe {
+		packet_id: 3,
+		properties: Vec::new(),
+		topic_filters: vec!(TopicFilter::new("a/b").unwrap())
+	};
+
+	assert_round_trip(packet, vec!(0xA2, 0x07, 0x00, 0x03, 0x00, 0x03, b'a', b'/', b'b'));
+}
+
+#[test]
+fn test_encode_empty_packets() {
+	assert_round_trip(Packet::PingRequest, vec!(0xC0, 0x00));
+	assert_round_trip(Packet::PingResponse, vec!(0xD0, 0x00));
+	assert_round_trip(Packet::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() }, vec!(0xE0, 0x00));
+}
+
+#[test]
+fn test_encode_mqtt5_packets() {
+	let version = ProtocolVersion::Mqtt5;
+
+	assert_version_round_trip(Packet::ConnectAck {
+		session_present: false,
+		reason_code: ReasonCode::NotAuthorized,
+		properties: vec!(Property::ReasonString("no".into()))
+	}, version, vec!(0x20, 0x08, 0x00, 0x87, 0x05, 0x1F, 0x00, 0x02, b'n', b'o'));
+
+	let packet = Packet::Publish(PublishPacket {
+		dup: false,
+		qos: QualityOfService::AtLeastOnce,
+		retain: false,
+		topic_name: TopicName::new("a").unwrap(),
+		packet_id: Some(1),
+		properties: vec!(Property::MessageExpiryInterval(60), Property::CorrelationData(vec!(0xFF))),
+		payload: vec!(0x01)
+	});
+
+	assert_version_round_trip(packet, version, vec!(
+		0x32, 0x10, // Fixed header
+		0x00, 0x01, b'a', // Topic Name
+		0x00, 0x01, // Packet ID
+		0x09, 0x02, 0x00, 0x00, 0x00, 0x3C, 0x09, 0x00, 0x01, 0xFF, // Properties
+		0x01 // Payload
+	));
+
+	assert_version_round_trip(Packet::PublishReceived { packet_id: 2, reason_code: ReasonCode::Success, properties: vec!() }, version, vec!(0x50, 0x02, 0x00, 0x02));
+	assert_version_round_trip(Packet::PublishRelease { packet_id: 3, reason_code: ReasonCode::PacketIdentifierNotFound, properties: vec!() }, version, vec!(0x62, 0x03, 0x00, 0x03, 0x92));
+	assert_version_round_trip(Packet::PublishComplete {
+		packet_id: 4,
+		reason_code: ReasonCode::Success,
+		properties: vec!(Property::UserProperty("a".into(), "b".into()))
+	}, version, vec!(0x70, 0x0B, 0x00, 0x04, 0x00, 0x07, 0x26, 0x00, 0x01, b'a', 0x00, 0x01, b'b'));
+
+	let packet = Packet::Subscribe {
+		packet_id: 10,
+		properties: vec!(Property::SubscriptionIdentifier(200)),
+		topics: vec!(
+			SubscribeTopic {
+				topic_filter: TopicFilter::new("a").unwrap(),
+				qos: QualityOfService::ExactlyOnce,
+				no_local: true,
+				retain_as_published: false,
+				retain_handling: RetainHandling::SendAtNewSubscribe
+			}
+		)
+	};
+
+	assert_version_round_trip(packet, version, vec!(0x82, 0x0A, 0x00, 0x0A, 0x03, 0x0B, 0xC8, 0x01, 0x00, 0x01, b'a', 0b00010110));
+
+	let packet = Packet::SubscribeAck {
+		packet_id: 10,
+		properties: vec!(),
+		return_codes: vec!(
+			SubscribeReturnCode::Success(QualityOfService::AtLeastOnce),
+			SubscribeReturnCode::Failure(ReasonCode::TopicFilterInvalid)
+		)
+	};
+
+	assert_version_round_trip(packet, version, vec!(0x90, 0x05, 0x00, 0x0A, 0x00, 0x01, 0x8F));
+
+	let packet = Packet::Unsubscribe {
+		packet_id: 3,
+		properties: vec!(),
+		topic_filters: vec!(TopicFilter::new("a").unwrap())
+	};
+
+	assert_version_round_trip(packet, version, vec!(0xA2, 0x06, 0x00, 0x03, 0x00, 0x00, 0x01, b'a'));
+
+	let packet = Packet::UnsubscribeAck {
+		packet_id: 3,
+		properties: vec!(),
+		reason_codes: vec!(ReasonCode::Success, ReasonCode::NoSubscriptionExisted)
+	};
+
+	assert_version_round_trip(packet, version, vec!(0xB0, 0x05, 0x00, 0x03, 0x00, 0x00, 0x11));
+
+	assert_version_round_trip(Packet::Disconnect { reason_code: ReasonCode::Success, properties: vec!() }, version, vec!(0xE0, 0x00));
+	assert_version_round_trip(Packet::Disconnect { reason_code: ReasonCode::KeepAliveTimeout, properties: vec!() }, version, vec!(0xE0, 0x01, 0x8D));
+	assert_version_round_trip(Packet::Auth {
+		reason_code: ReasonCode::ContinueAuthentication,
+		properties: vec!(Property::AuthenticationMethod("m".into()))
+	}, version, vec!(0xF0, 0x06, 0x18, 0x04, 0x15, 0x00, 0x01, b'm'));
+}
+
+#[test]
+fn test_encode_mqtt5_connect() {
+	let packet = Packet::Connect(ConnectPacket {
+		variable_header: ConnectVariableHeader {
+			protocol_name: "MQTT".into(),
+			protocol_level: 5,
+			connect_flags: ConnectFlags {
+				clean_session: true,
+				will_flag: true,
+				will_qos: QualityOfService::AtMostOnce,
+				will_retain: false,
+				password: false,
+				username: false
+			},
+			keep_alive: 10,
+			properties: vec!(Property::ReceiveMaximum(20))
+		},
+		payload: ConnectPayload {
+			client_id: "c".into(),
+			will_properties: vec!(Property::WillDelayInterval(5)),
+			will_topic: Some(TopicName::new("w").unwrap()),
+			will_message: Some(vec!()),
+			username: None,
+			password: None
+		}
+	});
+
+	// CONNECT is encoded for the version in its own header, whatever the connection's version
+	assert_round_trip(packet, vec!(
+		0x10, 0x1C, // Fixed header
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x05, // Protocol Level
+		0b00000110, // Connect Flags
+		0x00, 0x0A, // Keep alive
+		0x03, 0x21, 0x00, 0x14, // Properties
+		0x00, 0x01, b'c', // Client ID
+		0x05, 0x18, 0x00, 0x00, 0x00, 0x05, // Will Properties
+		0x00, 0x01, b'w', // Will Topic
+		0x00, 0x00 // Will Message
+	));
+}
+
+#[test]
+fn test_encode_mqtt5_fields_dropped_for_mqtt311() {
+	let mut buf = Vec::new();
+	encode_packet(&Packet::ConnectAck {
+		session_present: false,
+		reason_code: ReasonCode::Banned,
+		properties: vec!(Property::ReasonString("banned".into()))
+	}, ProtocolVersion::Mqtt311, &mut buf).unwrap();
+
+	assert_eq!(buf, vec!(0x20, 0x02, 0x00, 0x05));
+
+	let mut buf = Vec::new();
+	encode_packet(&Packet::SubscribeAck {
+		packet_id: 1,
+		properties: vec!(),
+		return_codes: vec!(SubscribeReturnCode::Failure(ReasonCode::QuotaExceeded))
+	}, ProtocolVersion::Mqtt311, &mut buf).unwrap();
+
+	assert_eq!(buf, vec!(0x90, 0x03, 0x00, 0x01, 0x80));
+}
+
+#[test]
+fn test_encode_too_large() {
+	let mut buf = Vec::new();
+	assert_eq!(encode_remaining_length(MAX_REMAINING_LENGTH + 1, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+	let packet = Packet::Publish(PublishPacket {
+		dup: false,
+		qos: QualityOfService::AtMostOnce,
+		retain: false,
+		topic_name: TopicName::new("a").unwrap(),
+		packet_id: None,
+		properties: vec!(Property::UserProperty("k".into(), "v".repeat(70_000))),
+		payload: vec!()
+	});
+
+	assert_eq!(encode_packet(&packet, ProtocolVersion::Mqtt5, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+	// AUTH can't be sent to an earlier version
+	let packet = Packet::Auth { reason_code: ReasonCode::Success, properties: vec!() };
+	assert_eq!(encode_packet(&packet, ProtocolVersion::Mqtt311, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+	// Nothing is written for a packet which fails
+	assert!(buf.is_empty());
+}
+
+#[test]
+fn test_encode_publish_packet_id_mismatch() {
+	let mut buf = Vec::new();
+
+	for &(qos, packet_id) in &[(QualityOfService::AtMostOnce, Some(1)), (QualityOfService::AtLeastOnce, None), (QualityOfService::ExactlyOnce, None)] {
+		let packet = Packet::Publish(PublishPacket {
+			dup: false,
+			qos: qos,
+			retain: false,
+			topic_name: TopicName::new("a").unwrap(),
+			packet_id: packet_id,
+			properties: vec!(),
+			payload: vec!(1)
+		});
+
+		assert_eq!(encode_packet(&packet, ProtocolVersion::Mqtt311, &mut buf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	}
+
+	assert!(buf.is_empty());
+}
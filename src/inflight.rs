@@ -0,0 +1,251 @@
+use super::protocol::{Packet, PublishPacket, QualityOfService, ReasonCode};
+
+use std::collections::{BTreeMap, HashMap};
+
+// Where an outgoing QoS 1 or 2 message has got to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outgoing {
+	// Waiting for PUBACK, or PUBREC at QoS 2
+	Publish(PublishPacket),
+	// QoS 2 only, PUBREL has been sent and we're waiting for PUBCOMP
+	Release(u16)
+}
+
+// QoS 1 and 2 messages sent to a client which haven't finished being acknowledged
+pub struct Inflight {
+	// Keyed by packet id, along with when the message was first sent
+	messages: HashMap<u16, (u64, Outgoing)>,
+	// Packet ids in the order their messages were first sent, for resending
+	order: BTreeMap<u64, u16>,
+	next_sequence: u64
+}
+
+impl Inflight {
+	pub fn new() -> Inflight {
+		Inflight {
+			messages: HashMap::new(),
+			order: BTreeMap::new(),
+			next_sequence: 0
+		}
+	}
+
+	// Only messages with a packet id can be acknowledged
+	pub fn insert(&mut self, publish: PublishPacket) {
+		let packet_id = publish.packet_id.expect("inflight messages need a packet id");
+		let sequence = self.next_sequence;
+		self.next_sequence += 1;
+
+		if let Some((replaced, _)) = self.messages.insert(packet_id, (sequence, Outgoing::Publish(publish))) {
+			self.order.remove(&replaced);
+		}
+
+		self.order.insert(sequence, packet_id);
+	}
+
+	pub fn contains(&self, packet_id: u16) -> bool {
+		self.messages.contains_key(&packet_id)
+	}
+
+	// Handles PUBACK, removing and returning the QoS 1 message with this packet id if there is one
+	pub fn acknowledge(&mut self, packet_id: u16) -> Option<PublishPacket> {
+		let is_qos_1 = match self.messages.get(&packet_id) {
+			Some(&(_, Outgoing::Publish(ref publish))) => publish.qos == QualityOfService::AtLeastOnce,
+			_ => false
+		};
+
+		if !is_qos_1 {
+			return None;
+		}
+
+		match self.remove(packet_id) {
+			Some(Outgoing::Publish(publish)) => Some(publish),
+			_ => None
+		}
+	}
+
+	// Handles PUBREC, moving the QoS 2 message with this packet id on to waiting for PUBCOMP.
+	// Returns false if there is no such message. A repeated PUBREC gets PUBREL again, so it also returns true.
+	pub fn received(&mut self, packet_id: u16) -> bool {
+		match self.messages.get_mut(&packet_id) {
+			Some(&mut (_, ref mut outgoing)) => {
+				let next = match *outgoing {
+					Outgoing::Publish(ref publish) if publish.qos == QualityOfService::ExactlyOnce => Outgoing::Release(packet_id),
+					Outgoing::Publish(_) => return false,
+					Outgoing::Release(_) => return true
+				};
+
+				// The message keeps its place so it's still resent in order
+				*outgoing = next;
+				true
+			}
+			None => false
+		}
+	}
+
+	// Handles PUBCOMP, finishing with the QoS 2 message with this packet id. Returns false if it wasn't waiting for PUBCOMP.
+	pub fn complete(&mut self, packet_id: u16) -> bool {
+		let is_released = match self.messages.get(&packet_id) {
+			Some(&(_, ref outgoing)) => *outgoing == Outgoing::Release(packet_id),
+			None => false
+		};
+
+		if is_released {
+			self.remove(packet_id);
+		}
+
+		is_released
+	}
+
+	// Drops a message whatever state it's in, for MQTT 5 clients which refuse it with an error reason code
+	pub fn discard(&mut self, packet_id: u16) -> Option<Outgoing> {
+		self.remove(packet_id)
+	}
+
+	// Packets to send to a client which has reconnected, in the order the messages were first sent.
+	// Messages which haven't been acknowledged are sent again with DUP set, and the rest get PUBREL again.
+	pub fn resend(&mut self) -> Vec<Packet> {
+		let messages = &mut self.messages;
+
+		self.order.values().map(|packet_id| {
+			match messages.get_mut(packet_id).unwrap().1 {
+				Outgoing::Publish(ref mut publish) => {
+					publish.dup = true;
+					Packet::Publish(publish.clone())
+				}
+				Outgoing::Release(packet_id) => {
+					Packet::PublishRelease {
+						packet_id: packet_id,
+						reason_code: ReasonCode::Success,
+						properties: Vec::new()
+					}
+				}
+			}
+		}).collect()
+	}
+
+	pub fn len(&self) -> usize {
+		self.messages.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.messages.is_empty()
+	}
+
+	fn remove(&mut self, packet_id: u16) -> Option<Outgoing> {
+		self.messages.remove(&packet_id).map(|(sequence, outgoing)| {
+			self.order.remove(&sequence);
+			outgoing
+		})
+	}
+}
+
+#[cfg(test)]
+use super::topic::TopicName;
+
+#[cfg(test)]
+fn publish(packet_id: u16, qos: QualityOfService) -> PublishPacket {
+	PublishPacket {
+		dup: false,
+		qos: qos,
+		retain: false,
+		topic_name: TopicName::new("a/b").unwrap(),
+		packet_id: Some(packet_id),
+		properties: Vec::new(),
+		payload: vec!(packet_id as u8)
+	}
+}
+
+#[test]
+fn test_acknowledge() {
+	let mut inflight = Inflight::new();
+
+	inflight.insert(publish(1, QualityOfService::AtLeastOnce));
+	inflight.insert(publish(2, QualityOfService::AtLeastOnce));
+	inflight.insert(publish(3, QualityOfService::AtLeastOnce));
+
+	assert!(inflight.contains(2));
+	assert_eq!(inflight.acknowledge(2), Some(publish(2, QualityOfService::AtLeastOnce)));
+	assert!(!inflight.contains(2));
+	assert_eq!(inflight.len(), 2);
+
+	// A second acknowledgement for the same packet id is ignored
+	assert_eq!(inflight.acknowledge(2), None);
+	assert_eq!(inflight.acknowledge(4), None);
+
+	assert_eq!(inflight.acknowledge(1), Some(publish(1, QualityOfService::AtLeastOnce)));
+	assert_eq!(inflight.acknowledge(3), Some(publish(3, QualityOfService::AtLeastOnce)));
+	assert!(inflight.is_empty());
+}
+
+#[test]
+fn test_exactly_once() {
+	let mut inflight = Inflight::new();
+
+	inflight.insert(publish(1, QualityOfService::ExactlyOnce));
+
+	// PUBACK and PUBCOMP don't apply until PUBREC
+	assert_eq!(inflight.acknowledge(1), None);
+	assert!(!inflight.complete(1));
+
+	assert!(inflight.received(1));
+	assert!(inflight.received(1));
+	assert!(!inflight.received(2));
+	assert!(inflight.contains(1));
+
+	assert!(inflight.complete(1));
+	assert!(!inflight.complete(1));
+	assert!(inflight.is_empty());
+
+	// PUBREC only applies to QoS 2
+	inflight.insert(publish(2, QualityOfService::AtLeastOnce));
+	assert!(!inflight.received(2));
+	assert!(inflight.discard(2).is_some());
+	assert!(inflight.is_empty());
+}
+
+#[test]
+fn test_resend() {
+	let mut inflight = Inflight::new();
+
+	inflight.insert(publish(5, QualityOfService::AtLeastOnce));
+	inflight.insert(publish(1, QualityOfService::AtLeastOnce));
+	inflight.insert(publish(3, QualityOfService::ExactlyOnce));
+	inflight.insert(publish(4, QualityOfService::ExactlyOnce));
+	inflight.acknowledge(1);
+	inflight.received(3);
+
+	let mut duplicate = publish(5, QualityOfService::AtLeastOnce);
+	duplicate.dup = true;
+
+	let mut duplicate_qos_2 = publish(4, QualityOfService::ExactlyOnce);
+	duplicate_qos_2.dup = true;
+
+	assert_eq!(inflight.resend(), vec!(
+		Packet::Publish(duplicate.clone()),
+		Packet::PublishRelease { packet_id: 3, reason_code: ReasonCode::Success, properties: Vec::new() },
+		Packet::Publish(duplicate_qos_2)
+	));
+
+	// The messages stay inflight until they're acknowledged
+	assert_eq!(inflight.len(), 3);
+	assert_eq!(inflight.acknowledge(5), Some(duplicate));
+}
+
+#[test]
+fn test_resend_reused_packet_id() {
+	let mut inflight = Inflight::new();
+
+	inflight.insert(publish(1, QualityOfService::AtLeastOnce));
+	inflight.insert(publish(2, QualityOfService::AtLeastOnce));
+	inflight.acknowledge(1);
+
+	// A packet id used again goes to the back, after the messages sent before it
+	inflight.insert(publish(1, QualityOfService::AtLeastOnce));
+
+	let packet_ids: Vec<Option<u16>> = inflight.resend().into_iter().map(|packet| match packet {
+		Packet::Publish(publish) => publish.packet_id,
+		packet => panic!("{:?}", packet)
+	}).collect();
+
+	assert_eq!(packet_ids, vec!(Some(2), Some(1)));
+}
@@ -7,24 +7,35 @@ extern crate mio;
 extern crate bytes;
 extern crate slab;
 
+mod client_state;
+mod config;
+mod encoder;
+mod inflight;
 mod mqtt_handler;
 mod parser;
+mod retained;
 mod session;
 mod session_state;
+mod subscription_tree;
 mod protocol;
+mod timer;
+mod topic;
+mod write_queue;
 
 use mio::tcp::*;
 use mio::{Poll};
 
+use config::Config;
 use mqtt_handler::MqttHandler;
 
 fn main() {
-	let port: u16 = 1883;
-	let address = format!("0.0.0.0:{}", port).parse().unwrap();
+	let config = Config::default();
+	let address = format!("0.0.0.0:{}", config.port).parse().unwrap();
 	let socket = TcpListener::bind(&address).unwrap();
 
 	let mut poll = Poll::new().expect("Failed to create Poll");
-	let mut server = MqttHandler::new(socket);
+	let port = config.port;
+	let mut server = MqttHandler::new(socket, config);
 
 	println!("Running MQTT server on port {}", port);
 	server.run(&mut poll).expect("Failed to run the server");
@@ -1,10 +1,19 @@
 extern crate mio;
 
-use super::session::{Session};
+use super::client_state::ClientState;
+use super::config::Config;
+use super::protocol::PublishPacket;
+use super::retained::RetainedMessages;
+use super::session::{Action, Session};
+use super::subscription_tree::SubscriptionTree;
+use super::timer::Timer;
 
+use std::collections::HashMap;
 use std::io;
 use std::io::{ErrorKind};
 use std::result::Result;
+use std::cmp;
+use std::time::{Duration, Instant};
 use std::usize;
 use mio::tcp::*;
 use mio::{Event, Events, Poll, PollOpt, Ready, Token};
@@ -14,16 +23,49 @@ type Slab<T> = slab::Slab<T, Token>;
 
 pub const SERVER_TOKEN: Token = mio::Token(usize::MAX-1);
 
+// What a timer deadline is for
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Timeout {
+	// The keep alive of a connection, or the time it has to send CONNECT. Tokens are reused
+	// once a session has gone, so the connection id says which connection it's for.
+	Connection(Token, u64),
+	// When the saved state of a disconnected client is thrown away
+	SessionExpiry(String)
+}
+
 pub struct MqttHandler {
 	socket: TcpListener,
-	sessions: Slab<Session>
+	config: Config,
+	sessions: Slab<Session>,
+	// Subscribers are identified by client id, so the subscriptions of a persistent session
+	// stay in place while its client is disconnected
+	subscriptions: SubscriptionTree<String>,
+	// The connection for each connected client. A second connection with the same client id takes over from the first.
+	clients: HashMap<String, Token>,
+	// The state of disconnected clients which asked for it to be kept, until it expires
+	saved_sessions: HashMap<String, ClientState>,
+	retained: RetainedMessages,
+	timer: Timer<Timeout>,
+	// Sessions found closed part way through handling an event. They're removed once it's finished
+	// with, so nothing is removed while its actions are still being performed.
+	closed: Vec<Token>,
+	// The id for the next connection accepted
+	next_connection_id: u64
 }
 
 impl MqttHandler {
-	pub fn new(socket: TcpListener) -> MqttHandler {
+	pub fn new(socket: TcpListener, config: Config) -> MqttHandler {
 		MqttHandler {
 			socket: socket,
-			sessions: Slab::with_capacity(2)
+			sessions: Slab::with_capacity(config.max_connections),
+			config: config,
+			subscriptions: SubscriptionTree::new(),
+			clients: HashMap::new(),
+			saved_sessions: HashMap::new(),
+			retained: RetainedMessages::new(),
+			timer: Timer::new(),
+			closed: Vec::new(),
+			next_connection_id: 1
 		}
 	}
 }
@@ -58,10 +100,18 @@ impl MqttHandler {
 						match self.sessions.vacant_entry() {
 							Some(entry) => {
 								let new_token = entry.index();
-								let new_session = Session::new(socket, new_token);
+								let connection_id = self.next_connection_id;
+								self.next_connection_id += 1;
+
+								let new_session = Session::new(socket, new_token, connection_id, &self.config);
 
 								try!(MqttHandler::register_new_connection(poll, &new_session, new_token));
 
+								// A connection which never sends CONNECT is closed once its time is up
+								if let Some(deadline) = new_session.keep_alive_deadline() {
+									self.timer.schedule(Timeout::Connection(new_token, connection_id), deadline);
+								}
+
 								entry.insert(new_session).index();
 							}
 							None => {
@@ -86,33 +136,297 @@ impl MqttHandler {
 			}
 			_ => {
 				match self.sessions.get_mut(token) {
-					Some(connection) => {
-						try!(connection.handle_event(poll, event_type));
-					}
+					Some(connection) => connection.handle_event(event_type),
 					None => println!("Tried to use a token that doesn't exist in the sessions slab: {:?}", token)
 				}
 
-				// We use this because we can't call self.sessions.remove inside of the match, and we don't want to use
-				// self.sessions[token] because it can cause a panic
-				let mut should_remove = false;
+				self.perform_actions(poll, token);
 
-				match self.sessions.get(token) {
-					Some(connection) => {
-						should_remove = connection.is_closed();
+				// A session which failed to flush has closed, and is removed along with the rest
+				if let Some(connection) = self.sessions.get_mut(token) {
+					if let Err(e) = connection.flush(poll) {
+						println!("Failed to flush {:?}, {:?}", token, e);
 					}
-					None => println!("Tried to use a token that doesn't exist in the sessions slab: {:?}", token)
 				}
 
-				if should_remove {
-					println!("Removing {:?} from sessions slab", token);
-					self.sessions.remove(token);
-				}
+				self.closed.push(token);
+				self.remove_closed(poll);
 
 				Ok(())
 			}
 		}
 	}
 
+	fn remove_if_closed(&mut self, poll: &mut Poll, token: Token) {
+		// We use this because we can't call self.sessions.remove inside of the match, and we don't want to use
+		// self.sessions[token] because it can cause a panic
+		let mut should_remove = false;
+
+		match self.sessions.get(token) {
+			Some(connection) => {
+				should_remove = connection.is_closed();
+			}
+			None => println!("Tried to use a token that doesn't exist in the sessions slab: {:?}", token)
+		}
+
+		if should_remove {
+			println!("Removing {:?} from sessions slab", token);
+
+			if let Some(mut session) = self.sessions.remove(token) {
+				self.timer.cancel(&Timeout::Connection(token, session.connection_id));
+
+				if let Some(client_id) = session.client_id.clone() {
+					// The client may have connected again already
+					if self.clients.get(&client_id) == Some(&token) {
+						self.clients.remove(&client_id);
+					}
+
+					let mut client_state = session.take_client_state();
+
+					if let Some(session_expiry) = session.session_expiry {
+						let expires_at = Instant::now() + session_expiry;
+
+						client_state.expires_at = Some(expires_at);
+						self.timer.schedule(Timeout::SessionExpiry(client_id.clone()), expires_at);
+						self.saved_sessions.insert(client_id, client_state);
+					} else {
+						for topic_filter in client_state.subscriptions.keys() {
+							self.subscriptions.remove(topic_filter, &client_id);
+						}
+					}
+				}
+
+				// Sessions which closed cleanly have already dropped their will
+				if let Some(will) = session.take_will() {
+					println!("Publishing the will for {:?} to {}", token, will.topic_name);
+					self.publish(poll, will, None);
+				}
+			}
+		}
+	}
+
+	// Removing a session can publish its will, which can close more sessions, so this carries on until there are none left
+	fn remove_closed(&mut self, poll: &mut Poll) {
+		while let Some(token) = self.closed.pop() {
+			self.remove_if_closed(poll, token);
+		}
+	}
+
+	// Performing an action can lead to more, such as resuming a session letting it handle
+	// the packets which arrived after CONNECT, so this carries on until there are none left
+	fn perform_actions(&mut self, poll: &mut Poll, token: Token) {
+		loop {
+			let (client_id, actions) = match self.sessions.get_mut(token) {
+				Some(session) => (session.client_id.clone().unwrap_or_default(), session.take_actions()),
+				None => return
+			};
+
+			if actions.is_empty() {
+				return;
+			}
+
+			for action in actions {
+				self.perform_action(poll, token, &client_id, action);
+			}
+		}
+	}
+
+	fn perform_action(&mut self, poll: &mut Poll, token: Token, client_id: &str, action: Action) {
+		match action {
+			Action::Subscribe(topic_filter, subscription) => {
+				self.subscriptions.insert(&topic_filter, client_id.to_string(), subscription);
+			}
+			Action::Unsubscribe(topic_filter) => {
+				self.subscriptions.remove(&topic_filter, &client_id.to_string());
+			}
+			Action::Connect { client_id, clean_session } => self.connect(poll, token, client_id, clean_session),
+			Action::Publish(publish) => self.publish(poll, publish, Some(client_id)),
+			Action::SendRetained(topic_filter, qos) => {
+				if let Some(session) = self.sessions.get_mut(token) {
+					for publish in self.retained.matches(&topic_filter) {
+						session.deliver(publish, qos, true);
+					}
+				}
+			}
+		}
+	}
+
+	// Hands a newly connected session the state from its client's last connection, unless it
+	// asked to start afresh. If that connection is still open it's closed first.
+	fn connect(&mut self, poll: &mut Poll, token: Token, client_id: String, clean_session: bool) {
+		let saved = match self.clients.get(&client_id).cloned() {
+			Some(existing) if existing != token => self.take_over(poll, existing, &client_id),
+			_ => {
+				self.timer.cancel(&Timeout::SessionExpiry(client_id.clone()));
+				self.saved_sessions.remove(&client_id)
+			}
+		};
+
+		let saved = if clean_session {
+			if let Some(saved) = saved {
+				for topic_filter in saved.subscriptions.keys() {
+					self.subscriptions.remove(topic_filter, &client_id);
+				}
+			}
+
+			None
+		} else {
+			saved
+		};
+
+		self.clients.insert(client_id, token);
+
+		let (connection_id, deadline) = match self.sessions.get_mut(token) {
+			Some(session) => {
+				session.resume(saved);
+				(session.connection_id, session.keep_alive_deadline())
+			}
+			None => return
+		};
+
+		// The keep alive replaces the deadline for sending CONNECT
+		match deadline {
+			Some(deadline) => self.timer.schedule(Timeout::Connection(token, connection_id), deadline),
+			None => self.timer.cancel(&Timeout::Connection(token, connection_id))
+		}
+	}
+
+	// Handles every deadline which has passed
+	fn check_timeouts(&mut self, poll: &mut Poll) {
+		let now = Instant::now();
+
+		for timeout in self.timer.expired(now) {
+			match timeout {
+				Timeout::Connection(token, connection_id) => self.check_keep_alive(token, connection_id, now),
+				Timeout::SessionExpiry(client_id) => self.expire_session(client_id, now)
+			}
+		}
+
+		self.remove_closed(poll);
+	}
+
+	// Closes a session which has passed its keep alive. A session which has heard from its
+	// client since its deadline was scheduled is given a new one.
+	fn check_keep_alive(&mut self, token: Token, connection_id: u64, now: Instant) {
+		// The session may have gone, and its token may have been given to another connection since
+		let deadline = match self.sessions.get_mut(token) {
+			Some(session) if session.connection_id == connection_id => session.check_keep_alive(now),
+			_ => return
+		};
+
+		match deadline {
+			Some(deadline) => self.timer.schedule(Timeout::Connection(token, connection_id), deadline),
+			None => self.closed.push(token)
+		}
+	}
+
+	// Throws away the saved state of a client which hasn't reconnected in time, along with its subscriptions
+	fn expire_session(&mut self, client_id: String, now: Instant) {
+		let expired = match self.saved_sessions.get(&client_id) {
+			Some(saved) => saved.expires_at.map_or(false, |expires_at| expires_at <= now),
+			None => false
+		};
+
+		if !expired {
+			return;
+		}
+
+		println!("The session for {} has expired", client_id);
+
+		if let Some(saved) = self.saved_sessions.remove(&client_id) {
+			for topic_filter in saved.subscriptions.keys() {
+				self.subscriptions.remove(topic_filter, &client_id);
+			}
+		}
+	}
+
+	// Closes the existing connection for a client, returning its state if it would have been saved
+	fn take_over(&mut self, poll: &mut Poll, existing: Token, client_id: &str) -> Option<ClientState> {
+		let client_state = match self.sessions.get_mut(existing) {
+			Some(session) => {
+				let persistent = session.session_expiry.is_some();
+				let client_state = session.take_over(self.config.will_on_takeover);
+
+				if let Err(e) = session.flush(poll) {
+					println!("Failed to flush {:?}, {:?}", existing, e);
+				}
+
+				if persistent {
+					Some(client_state)
+				} else {
+					// A session which wouldn't have been saved ends with its connection
+					for topic_filter in client_state.subscriptions.keys() {
+						self.subscriptions.remove(topic_filter, &client_id.to_string());
+					}
+
+					None
+				}
+			}
+			None => None
+		};
+
+		self.clients.remove(client_id);
+
+		// Its will isn't published until it's removed, which waits until the new session has taken
+		// its place. Otherwise the new session would miss the will, or it would be queued for nobody.
+		if !self.closed.contains(&existing) {
+			self.closed.push(existing);
+		}
+
+		client_state
+	}
+
+	// The publisher is the client id of the session which sent the message, which wills don't have
+	fn publish(&mut self, poll: &mut Poll, publish: PublishPacket, publisher: Option<&str>) {
+		if publish.retain {
+			self.retained.update(&publish);
+		}
+
+		self.route(poll, &publish, publisher);
+	}
+
+	// Sends a published message to every session with a matching subscription. RETAIN is
+	// cleared unless the subscription asked for it as published.
+	fn route(&mut self, poll: &mut Poll, publish: &PublishPacket, publisher: Option<&str>) {
+		let publisher = publisher.map(|client_id| client_id.to_string());
+		let subscribers = self.subscriptions.matches(&publish.topic_name, publisher.as_ref());
+
+		for (client_id, subscription) in subscribers {
+			let retain = publish.retain && subscription.retain_as_published;
+
+			let subscriber = match self.clients.get(&client_id) {
+				Some(&subscriber) => subscriber,
+				None => {
+					// Disconnected clients with a saved session get the message when they reconnect
+					match self.saved_sessions.get_mut(&client_id) {
+						Some(saved) => {
+							if !saved.queue(publish, subscription.qos, retain, self.config.max_queued_messages) {
+								println!("The queue for {} is full, dropping a message for {}", client_id, publish.topic_name);
+							}
+						}
+						None => println!("Subscription for {} outlived its session", client_id)
+					}
+
+					continue;
+				}
+			};
+
+			if let Some(session) = self.sessions.get_mut(subscriber) {
+				session.deliver(publish, subscription.qos, retain);
+
+				if let Err(e) = session.flush(poll) {
+					println!("Failed to flush {:?}, {:?}", subscriber, e);
+				}
+
+				// Writing can fail if the subscriber's connection has gone. The subscriber may be the
+				// session which published the message, so it's left in place until its event is handled.
+				if session.is_closed() && !self.closed.contains(&subscriber) {
+					self.closed.push(subscriber);
+				}
+			}
+		}
+	}
+
 	fn register(&mut self, poll: &mut Poll) -> io::Result<()> {
 		poll
 		.register(&self.socket, SERVER_TOKEN, Ready::readable(), PollOpt::edge())
@@ -135,23 +449,611 @@ impl MqttHandler {
 		})
 	}
 
+	// Waits for events and handles them. It wakes up in time for the next deadline,
+	// or after max_wait if that's sooner, and waits indefinitely if there's neither.
+	fn turn(&mut self, poll: &mut Poll, events: &mut Events, max_wait: Option<Duration>) -> io::Result<()> {
+		let timeout = match (self.timer.timeout(Instant::now()), max_wait) {
+			(Some(timeout), Some(max_wait)) => Some(cmp::min(timeout, max_wait)),
+			(timeout, max_wait) => timeout.or(max_wait)
+		};
+
+		try!(poll.poll(events, timeout));
+
+		for event in events.iter() {
+			match self.handle_event(poll, event) {
+				Ok(_) => (),
+				Err(MqttError::Io(e)) => println!("Encountered IO error: {:?}", e),
+				Err(MqttError::TooManyConnections) => println!("Too many connections for the server to handle!")
+			}
+		}
+
+		self.check_timeouts(poll);
+		Ok(())
+	}
+
 	pub fn run(&mut self, poll: &mut Poll) -> io::Result<()> {
 		let mut events = Events::with_capacity(1024);
 
 		try!(self.register(poll));
 
 		loop {
-			try!(poll.poll(&mut events, None)); // None means no timeout
+			try!(self.turn(poll, &mut events, None));
+			println!("Tick!");
+		}
+	}
+}
+
+#[cfg(test)]
+use super::encoder::encode_packet;
+#[cfg(test)]
+use super::parser::packet_parser;
+#[cfg(test)]
+use super::protocol::{ConnectPacket, Packet, Property, ProtocolVersion, QualityOfService, ReasonCode, SubscribeReturnCode, SubscribeTopic};
+#[cfg(test)]
+use super::session::{connect_packet, publish};
+#[cfg(test)]
+use super::topic::{TopicFilter, TopicName};
+#[cfg(test)]
+use nom::IResult;
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::mem;
+#[cfg(test)]
+use std::net;
+#[cfg(test)]
+use std::thread;
+
+// A handler listening on a loopback port, driven by the test rather than by run
+#[cfg(test)]
+struct TestBroker {
+	handler: MqttHandler,
+	poll: Poll,
+	events: Events,
+	address: net::SocketAddr
+}
+
+#[cfg(test)]
+impl TestBroker {
+	fn new(config: Config) -> TestBroker {
+		let socket = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+		let address = socket.local_addr().unwrap();
+		let mut poll = Poll::new().unwrap();
+		let mut handler = MqttHandler::new(socket, config);
+
+		handler.register(&mut poll).unwrap();
+
+		TestBroker {
+			handler: handler,
+			poll: poll,
+			events: Events::with_capacity(1024),
+			address: address
+		}
+	}
+
+	// Handles events until there are no more for a little while
+	fn settle(&mut self) {
+		loop {
+			self.handler.turn(&mut self.poll, &mut self.events, Some(Duration::from_millis(50))).unwrap();
+
+			if self.events.is_empty() {
+				return;
+			}
+		}
+	}
+}
+
+// The client end of a connection to a TestBroker
+#[cfg(test)]
+struct TestClient {
+	stream: net::TcpStream,
+	version: ProtocolVersion,
+	// Bytes read which don't make up a whole packet yet
+	buf: Vec<u8>,
+	// Packets read which haven't been returned by received yet
+	unread: Vec<Packet>,
+	closed: bool
+}
+
+#[cfg(test)]
+impl TestClient {
+	fn new(broker: &mut TestBroker, version: ProtocolVersion) -> TestClient {
+		let stream = net::TcpStream::connect(broker.address).unwrap();
+		stream.set_nonblocking(true).unwrap();
+		broker.settle();
+
+		TestClient {
+			stream: stream,
+			version: version,
+			buf: Vec::new(),
+			unread: Vec::new(),
+			closed: false
+		}
+	}
+
+	// Connects with the given client id and clean session flag, returning whether the broker had a session for it
+	fn connect(broker: &mut TestBroker, version: ProtocolVersion, client_id: &str, clean_session: bool) -> (TestClient, bool) {
+		let mut connect = connect_packet(version, client_id);
+		connect.variable_header.connect_flags.clean_session = clean_session;
+
+		match TestClient::connect_with(broker, connect) {
+			(client, Packet::ConnectAck { session_present, reason_code: ReasonCode::Success, .. }) => (client, session_present),
+			(_, packet) => panic!("Expected CONNACK, got {:?}", packet)
+		}
+	}
+
+	// Sends a CONNECT, returning the first packet the broker sends back
+	fn connect_with(broker: &mut TestBroker, connect: ConnectPacket) -> (TestClient, Packet) {
+		let version = connect.variable_header.protocol_version().unwrap();
+		let mut client = TestClient::new(broker, version);
+
+		client.send(broker, &Packet::Connect(connect));
+
+		// Anything sent straight after the CONNACK is left for received
+		let mut packets = client.received();
+		let packet = packets.remove(0);
+		client.unread = packets;
+
+		(client, packet)
+	}
+
+	fn disconnect(&mut self, broker: &mut TestBroker) {
+		self.send(broker, &Packet::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() });
+		assert!(self.is_closed());
+	}
+
+	// Sends a packet and lets the broker handle it
+	fn send(&mut self, broker: &mut TestBroker, packet: &Packet) {
+		let mut buf = Vec::new();
+		encode_packet(packet, self.version, &mut buf).unwrap();
+		self.stream.write_all(&buf).unwrap();
+		broker.settle();
+	}
+
+	fn subscribe(&mut self, broker: &mut TestBroker, filter: &str, qos: QualityOfService) {
+		self.send(broker, &Packet::Subscribe {
+			packet_id: 1,
+			properties: Vec::new(),
+			topics: vec!(SubscribeTopic::new(TopicFilter::new(filter).unwrap(), qos))
+		});
+
+		assert_eq!(self.received(), vec!(Packet::SubscribeAck {
+			packet_id: 1,
+			properties: Vec::new(),
+			return_codes: vec!(SubscribeReturnCode::Success(qos))
+		}));
+	}
+
+	// Every whole packet the broker has sent since the last call
+	fn received(&mut self) -> Vec<Packet> {
+		let mut chunk = [0; 4096];
+
+		loop {
+			match self.stream.read(&mut chunk) {
+				Ok(0) => {
+					self.closed = true;
+					break;
+				}
+				Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) => panic!("{:?}", e)
+			}
+		}
+
+		let mut packets = mem::replace(&mut self.unread, Vec::new());
+		let mut consumed = 0;
 
-			for event in &events {
-				match self.handle_event(poll, event) {
-					Ok(_) => (),
-					Err(MqttError::Io(e)) => println!("Encountered IO error: {:?}", e),
-					Err(MqttError::TooManyConnections) => println!("Too many connections for the server to handle!")
+		loop {
+			match packet_parser(&self.buf[consumed..], self.version) {
+				IResult::Done(rest, packet) => {
+					packets.push(packet);
+					consumed = self.buf.len() - rest.len();
 				}
+				IResult::Incomplete(_) => break,
+				e => panic!("{:?}", e)
 			}
+		}
 
-			println!("Tick!");
+		self.buf.drain(..consumed);
+		packets
+	}
+
+	// Whether the broker has closed the connection, after reading anything it sent first
+	fn is_closed(&mut self) -> bool {
+		self.received();
+		self.closed
+	}
+}
+
+#[test]
+fn test_route() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "publisher", true);
+	let (mut wildcard, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "wildcard", true);
+	let (mut exact, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "exact", true);
+
+	wildcard.subscribe(&mut broker, "a/+", QualityOfService::ExactlyOnce);
+	exact.subscribe(&mut broker, "a/b", QualityOfService::AtLeastOnce);
+
+	publisher.send(&mut broker, &Packet::Publish(publish("a/b", QualityOfService::AtLeastOnce, Some(7))));
+	assert_eq!(publisher.received(), vec!(Packet::PublishAck { packet_id: 7, reason_code: ReasonCode::Success, properties: Vec::new() }));
+
+	// Each subscriber numbers the message itself
+	assert_eq!(wildcard.received(), vec!(Packet::Publish(publish("a/b", QualityOfService::AtLeastOnce, Some(1)))));
+	assert_eq!(exact.received(), vec!(Packet::Publish(publish("a/b", QualityOfService::AtLeastOnce, Some(1)))));
+
+	publisher.send(&mut broker, &Packet::Publish(publish("a/c", QualityOfService::AtMostOnce, None)));
+	assert_eq!(wildcard.received(), vec!(Packet::Publish(publish("a/c", QualityOfService::AtMostOnce, None))));
+	assert_eq!(exact.received(), vec!());
+}
+
+#[test]
+fn test_route_downgrade() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "publisher", true);
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", true);
+
+	subscriber.subscribe(&mut broker, "a/b", QualityOfService::AtMostOnce);
+
+	// The message goes out at the lower of the two QoS
+	publisher.send(&mut broker, &Packet::Publish(publish("a/b", QualityOfService::ExactlyOnce, Some(1))));
+	assert_eq!(subscriber.received(), vec!(Packet::Publish(publish("a/b", QualityOfService::AtMostOnce, None))));
+}
+
+#[test]
+fn test_no_local() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "publisher", true);
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "subscriber", true);
+
+	let mut topic = SubscribeTopic::new(TopicFilter::new("a").unwrap(), QualityOfService::AtMostOnce);
+	topic.no_local = true;
+
+	for client in vec!(&mut publisher, &mut subscriber) {
+		client.send(&mut broker, &Packet::Subscribe { packet_id: 1, properties: Vec::new(), topics: vec!(topic.clone()) });
+		client.received();
+	}
+
+	// The publisher doesn't get its own message back, but other no_local subscribers do
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::AtMostOnce, None)));
+	assert_eq!(publisher.received(), vec!());
+	assert_eq!(subscriber.received(), vec!(Packet::Publish(publish("a", QualityOfService::AtMostOnce, None))));
+}
+
+#[test]
+fn test_retain_as_published() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "publisher", true);
+	let (mut cleared, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "cleared", true);
+	let (mut kept, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "kept", true);
+
+	cleared.subscribe(&mut broker, "a", QualityOfService::AtMostOnce);
+
+	let mut topic = SubscribeTopic::new(TopicFilter::new("a").unwrap(), QualityOfService::AtMostOnce);
+	topic.retain_as_published = true;
+	kept.send(&mut broker, &Packet::Subscribe { packet_id: 1, properties: Vec::new(), topics: vec!(topic) });
+	kept.received();
+
+	let mut message = publish("a", QualityOfService::AtMostOnce, None);
+	message.retain = true;
+	publisher.send(&mut broker, &Packet::Publish(message.clone()));
+
+	assert_eq!(cleared.received(), vec!(Packet::Publish(publish("a", QualityOfService::AtMostOnce, None))));
+	assert_eq!(kept.received(), vec!(Packet::Publish(message)));
+}
+
+#[test]
+fn test_route_to_closed_publisher() {
+	let mut broker = TestBroker::new(Config::default());
+	let mut client = TestClient::new(&mut broker, ProtocolVersion::Mqtt311);
+
+	// Everything arrives in one read, so the session is closed before any of its actions are performed,
+	// and the message is routed to it in the middle of them
+	let packets = vec!(
+		Packet::Connect(connect_packet(ProtocolVersion::Mqtt311, "a")),
+		Packet::Subscribe { packet_id: 1, properties: Vec::new(), topics: vec!(SubscribeTopic::new(TopicFilter::new("a").unwrap(), QualityOfService::AtMostOnce)) },
+		Packet::Publish(publish("a", QualityOfService::AtMostOnce, None)),
+		Packet::Subscribe { packet_id: 2, properties: Vec::new(), topics: vec!(SubscribeTopic::new(TopicFilter::new("b").unwrap(), QualityOfService::AtMostOnce)) },
+		Packet::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() }
+	);
+
+	let mut buf = Vec::new();
+
+	for packet in &packets {
+		encode_packet(packet, ProtocolVersion::Mqtt311, &mut buf).unwrap();
+	}
+
+	client.stream.write_all(&buf).unwrap();
+	broker.settle();
+
+	// Both subscriptions go with the session
+	assert!(client.is_closed());
+	assert_eq!(broker.handler.sessions.len(), 0);
+	assert_eq!(broker.handler.subscriptions.len(), 0);
+}
+
+#[test]
+fn test_connect_timeout() {
+	let mut config = Config::default();
+	config.connect_timeout = Duration::from_millis(100);
+
+	let mut broker = TestBroker::new(config);
+	let mut client = TestClient::new(&mut broker, ProtocolVersion::Mqtt311);
+	assert_eq!(broker.handler.sessions.len(), 1);
+
+	thread::sleep(Duration::from_millis(150));
+	broker.settle();
+
+	assert!(client.is_closed());
+	assert_eq!(broker.handler.sessions.len(), 0);
+	assert_eq!(broker.handler.timer.len(), 0);
+}
+
+#[test]
+fn test_connect_replaces_connect_timeout() {
+	let mut config = Config::default();
+	config.connect_timeout = Duration::from_millis(100);
+
+	let mut broker = TestBroker::new(config);
+	let (mut client, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "a", true);
+
+	// The 60 second keep alive applies instead
+	thread::sleep(Duration::from_millis(150));
+	broker.settle();
+
+	assert!(!client.is_closed());
+	assert_eq!(broker.handler.timer.len(), 1);
+}
+
+#[test]
+fn test_persistent_session() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "publisher", true);
+
+	let (mut subscriber, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert!(!session_present);
+	subscriber.subscribe(&mut broker, "a", QualityOfService::AtLeastOnce);
+	subscriber.disconnect(&mut broker);
+
+	// The subscription stays, and QoS 1 and 2 messages are kept for the client
+	assert_eq!(broker.handler.saved_sessions.len(), 1);
+	assert_eq!(broker.handler.subscriptions.len(), 1);
+
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(1))));
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::AtMostOnce, None)));
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::ExactlyOnce, Some(2))));
+
+	let (mut subscriber, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert!(session_present);
+	assert_eq!(subscriber.received(), vec!(
+		Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(1))),
+		Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(2)))
+	));
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+
+	// The unacknowledged messages are kept when it disconnects again, and sent with DUP set when it's back
+	subscriber.send(&mut broker, &Packet::PublishAck { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() });
+	subscriber.disconnect(&mut broker);
+
+	let (mut subscriber, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert!(session_present);
+
+	let mut duplicate = publish("a", QualityOfService::AtLeastOnce, Some(2));
+	duplicate.dup = true;
+	assert_eq!(subscriber.received(), vec!(Packet::Publish(duplicate)));
+
+	// A clean session starts afresh and isn't kept
+	subscriber.disconnect(&mut broker);
+
+	let (mut subscriber, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", true);
+	assert!(!session_present);
+	assert_eq!(broker.handler.subscriptions.len(), 0);
+
+	subscriber.disconnect(&mut broker);
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+}
+
+#[test]
+fn test_resend_on_reconnect() {
+	let mut broker = TestBroker::new(Config::default());
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "publisher", true);
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	subscriber.subscribe(&mut broker, "a", QualityOfService::ExactlyOnce);
+
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(1))));
+	publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::ExactlyOnce, Some(2))));
+	assert_eq!(subscriber.received().len(), 2);
+
+	// The connection drops with the QoS 1 message unacknowledged and the QoS 2 message part way through
+	subscriber.send(&mut broker, &Packet::PublishReceived { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() });
+	assert_eq!(subscriber.received(), vec!(Packet::PublishRelease { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() }));
+	drop(subscriber);
+	broker.settle();
+
+	// Each is picked up where it was left, in the order the messages were first sent
+	let (mut subscriber, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert!(session_present);
+
+	let mut duplicate = publish("a", QualityOfService::AtLeastOnce, Some(1));
+	duplicate.dup = true;
+
+	assert_eq!(subscriber.received(), vec!(
+		Packet::Publish(duplicate),
+		Packet::PublishRelease { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() }
+	));
+
+	subscriber.send(&mut broker, &Packet::PublishAck { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() });
+	subscriber.send(&mut broker, &Packet::PublishComplete { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() });
+	subscriber.disconnect(&mut broker);
+
+	// Nothing is left to send again
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert_eq!(subscriber.received(), vec!());
+}
+
+#[test]
+fn test_queue_limit() {
+	let mut config = Config::default();
+	config.max_queued_messages = 2;
+
+	let mut broker = TestBroker::new(config);
+	let (mut publisher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "publisher", true);
+
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	subscriber.subscribe(&mut broker, "a", QualityOfService::AtLeastOnce);
+	subscriber.disconnect(&mut broker);
+
+	for packet_id in 1..4 {
+		publisher.send(&mut broker, &Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(packet_id))));
+	}
+
+	// Messages published once the queue is full are dropped
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert_eq!(subscriber.received(), vec!(
+		Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(1))),
+		Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(2)))
+	));
+}
+
+#[test]
+fn test_session_expiry() {
+	let mut config = Config::default();
+	config.session_expiry = Duration::from_millis(100);
+
+	let mut broker = TestBroker::new(config);
+	let (mut subscriber, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	subscriber.subscribe(&mut broker, "a", QualityOfService::AtLeastOnce);
+	subscriber.disconnect(&mut broker);
+	assert_eq!(broker.handler.saved_sessions.len(), 1);
+
+	thread::sleep(Duration::from_millis(150));
+	broker.settle();
+
+	// The state and its subscriptions are gone
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+	assert_eq!(broker.handler.subscriptions.len(), 0);
+	assert_eq!(broker.handler.timer.len(), 0);
+
+	let (_, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "subscriber", false);
+	assert!(!session_present);
+}
+
+#[test]
+fn test_mqtt5_session_expiry_interval() {
+	let mut config = Config::default();
+	config.session_expiry = Duration::from_secs(60);
+
+	let mut broker = TestBroker::new(config);
+
+	let unsupported = vec!(Property::SubscriptionIdentifierAvailable(0), Property::SharedSubscriptionAvailable(0));
+
+	// 0 means the state isn't kept, whatever the clean start flag says
+	let mut connect = connect_packet(ProtocolVersion::Mqtt5, "a");
+	connect.variable_header.connect_flags.clean_session = false;
+
+	let (mut client, connect_ack) = TestClient::connect_with(&mut broker, connect.clone());
+	assert_eq!(connect_ack, Packet::ConnectAck { session_present: false, reason_code: ReasonCode::Success, properties: unsupported.clone() });
+	client.disconnect(&mut broker);
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+
+	// An interval within the limit is used as it is
+	connect.variable_header.properties = vec!(Property::SessionExpiryInterval(30));
+
+	let (mut client, connect_ack) = TestClient::connect_with(&mut broker, connect.clone());
+	assert_eq!(connect_ack, Packet::ConnectAck { session_present: false, reason_code: ReasonCode::Success, properties: unsupported.clone() });
+	client.disconnect(&mut broker);
+	assert_eq!(broker.handler.saved_sessions.len(), 1);
+
+	// Anything longer, including never expiring, is cut down to the limit and the client is told
+	connect.variable_header.properties = vec!(Property::SessionExpiryInterval(0xFFFFFFFF));
+
+	let (_, connect_ack) = TestClient::connect_with(&mut broker, connect);
+	assert_eq!(connect_ack, Packet::ConnectAck {
+		session_present: true,
+		reason_code: ReasonCode::Success,
+		properties: vec!(Property::SessionExpiryInterval(60), Property::SubscriptionIdentifierAvailable(0), Property::SharedSubscriptionAvailable(0))
+	});
+}
+
+#[cfg(test)]
+fn connect_with_will(version: ProtocolVersion, client_id: &str, clean_session: bool) -> ConnectPacket {
+	let mut connect = connect_packet(version, client_id);
+	connect.variable_header.connect_flags.clean_session = clean_session;
+	connect.variable_header.connect_flags.will_flag = true;
+	connect.payload.will_topic = Some(TopicName::new("will").unwrap());
+	connect.payload.will_message = Some(b"hello".to_vec());
+	connect
+}
+
+#[test]
+fn test_takeover_will() {
+	for &will_on_takeover in &[true, false] {
+		let mut config = Config::default();
+		config.will_on_takeover = will_on_takeover;
+
+		let mut broker = TestBroker::new(config);
+		let (mut watcher, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "watcher", true);
+		watcher.subscribe(&mut broker, "will", QualityOfService::AtMostOnce);
+
+		let (mut old, _) = TestClient::connect_with(&mut broker, connect_with_will(ProtocolVersion::Mqtt311, "a", true));
+		let (mut new, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "a", true);
+
+		// Earlier versions have no way to say why, so the old connection just closes
+		assert!(old.is_closed());
+		assert!(!new.is_closed());
+		assert_eq!(broker.handler.sessions.len(), 2);
+		assert_eq!(broker.handler.clients.len(), 2);
+
+		if will_on_takeover {
+			assert_eq!(watcher.received(), vec!(Packet::Publish(publish("will", QualityOfService::AtMostOnce, None))));
+		} else {
+			assert_eq!(watcher.received(), vec!());
 		}
 	}
 }
+
+#[test]
+fn test_takeover_persistent() {
+	let mut broker = TestBroker::new(Config::default());
+
+	// The old connection subscribes to its own will
+	let (mut old, _) = TestClient::connect_with(&mut broker, connect_with_will(ProtocolVersion::Mqtt311, "a", false));
+	old.subscribe(&mut broker, "will", QualityOfService::AtLeastOnce);
+
+	// The new connection carries on with its subscription, so it gets the will
+	let (mut new, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "a", false);
+	assert!(session_present);
+	assert!(old.is_closed());
+	assert_eq!(new.received(), vec!(Packet::Publish(publish("will", QualityOfService::AtMostOnce, None))));
+	assert_eq!(broker.handler.subscriptions.len(), 1);
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+}
+
+#[test]
+fn test_takeover_clean() {
+	let mut broker = TestBroker::new(Config::default());
+
+	let (mut old, _) = TestClient::connect_with(&mut broker, connect_with_will(ProtocolVersion::Mqtt311, "a", false));
+	old.subscribe(&mut broker, "will", QualityOfService::AtLeastOnce);
+
+	// Starting afresh drops the old subscription, so the will goes nowhere
+	let (mut new, session_present) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt311, "a", true);
+	assert!(!session_present);
+	assert!(old.is_closed());
+	assert_eq!(new.received(), vec!());
+	assert_eq!(broker.handler.subscriptions.len(), 0);
+	assert_eq!(broker.handler.saved_sessions.len(), 0);
+}
+
+#[test]
+fn test_takeover_mqtt5() {
+	let mut broker = TestBroker::new(Config::default());
+
+	let (mut old, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "a", true);
+	let (_new, _) = TestClient::connect(&mut broker, ProtocolVersion::Mqtt5, "a", true);
+
+	// MQTT 5 clients are told why they're being disconnected
+	assert_eq!(old.received(), vec!(Packet::Disconnect { reason_code: ReasonCode::SessionTakenOver, properties: Vec::new() }));
+	assert!(old.is_closed());
+	assert_eq!(broker.handler.sessions.len(), 1);
+}
@@ -1,13 +1,26 @@
 use std::str;
 use std::convert::TryFrom;
-use nom::{be_u8, be_u16, Consumer, ConsumerState, ErrorKind, Input, MemProducer, Move, Needed, Producer, IResult};
+use nom::{be_u8, be_u16, be_u32, rest, ErrorKind, Needed, IResult};
 use nom::Err;
 use nom::Err::NodePosition;
 use nom::ErrorKind::Custom;
 
-use protocol::{ConnectVariableHeader, ConnectPayload, ControlPacketType, MqttParseError, FixedHeader, FirstByteData, VariableHeader, Payload};
+use protocol::{ConnectFlags, ConnectVariableHeader, ConnectPayload, ConnectPacket, ConnectReturnCode, ControlPacketType, DecodeError, MqttParseError, FixedHeader, PacketField};
+use protocol::{Packet, PublishPacket, SubscribeTopic, SubscribeReturnCode, QualityOfService, RetainHandling};
+use protocol::{Property, PropertyIdentifier, ProtocolVersion, ReasonCode};
+use topic::{TopicError, TopicFilter, TopicName};
 
-fn first_byte_parser(input: &[u8]) -> IResult<&[u8], FirstByteData, MqttParseError> {
+// Tags any error from a field's parser with the field, and the input where the field started
+macro_rules! field (
+	($i:expr, $field:expr, $submac:ident!( $($args:tt)* )) => (
+		add_error!($i, ErrorKind::Custom(MqttParseError::InvalidField($field)), $submac!($($args)*))
+	);
+	($i:expr, $field:expr, $f:expr) => (
+		field!($i, $field, call!($f))
+	);
+);
+
+fn first_byte_parser(input: &[u8]) -> IResult<&[u8], (ControlPacketType, u8), MqttParseError> {
 	if input.len() < 1 {
 		IResult::Incomplete(Needed::Size(1))
 	} else {
@@ -15,13 +28,22 @@ fn first_byte_parser(input: &[u8]) -> IResult<&[u8], FirstByteData, MqttParseErr
 
 		match ControlPacketType::try_from(((first_byte & 0b11110000) >> 4)) {
 			Ok(control_type) => {
-				IResult::Done(&input[1..], FirstByteData {
-					control_type: control_type,
-					bit_0: first_byte & 0b00001000 == 0b00001000,
-					bit_1: first_byte & 0b00000100 == 0b00000100,
-					bit_2: first_byte & 0b00000010 == 0b00000010,
-					bit_3: first_byte & 0b00000001 == 0b00000001
-				})
+				let flags = first_byte & 0b00001111;
+
+				// PUBLISH carries its own flags here, every other type has fixed reserved bits
+				let valid_flags = match control_type {
+					ControlPacketType::Publish => true,
+					ControlPacketType::PublishRelease |
+					ControlPacketType::Subscribe |
+					ControlPacketType::Unsubscribe => flags == 0b0010,
+					_ => flags == 0b0000
+				};
+
+				if valid_flags {
+					IResult::Done(&input[1..], (control_type, flags))
+				} else {
+					IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidFixedHeaderFlags)))
+				}
 			}
 			Err(e) => {
 				IResult::Error(Err::Code(ErrorKind::Custom(e)))
@@ -30,7 +52,9 @@ fn first_byte_parser(input: &[u8]) -> IResult<&[u8], FirstByteData, MqttParseErr
 	}
 }
 
-fn remaining_length_parser(input: &[u8]) -> IResult<&[u8], u32, MqttParseError> {
+// The remaining length, property lengths and subscription identifiers all use
+// this encoding, so the error to report is passed in
+fn variable_byte_integer_parser(input: &[u8], error: MqttParseError) -> IResult<&[u8], u32, MqttParseError> {
 	if input.len() < 1 {
 		IResult::Incomplete(Needed::Size(1))
 	} else {
@@ -55,25 +79,34 @@ fn remaining_length_parser(input: &[u8]) -> IResult<&[u8], u32, MqttParseError>
 			value += ((encoded_byte & 0b01111111) as u32) * multiplier;
 			multiplier *= 128;
 
-			if multiplier > (128 * 128 * 128) {
-				return IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidRemainingLength)));
-			}
-
 			if encoded_byte & 0b10000000 == 0b00000000 {
 				break;
 			}
+
+			// A fourth byte with the continuation bit set would make the length longer than the spec allows
+			if multiplier > (128 * 128 * 128) {
+				return IResult::Error(Err::Code(ErrorKind::Custom(error)));
+			}
 		}
 
 		IResult::Done(&input[consumed_bytes..], value)
 	}
 }
 
+fn remaining_length_parser(input: &[u8]) -> IResult<&[u8], u32, MqttParseError> {
+	variable_byte_integer_parser(input, MqttParseError::InvalidRemainingLength)
+}
+
 named!(pub fixed_header_parser<&[u8], FixedHeader, MqttParseError>,
 	chain!(
-		first_byte_data: first_byte_parser ~
+		first_byte: first_byte_parser ~
 		remaining_length: remaining_length_parser,
 		|| {
-			FixedHeader::from_first_byte_data(first_byte_data, remaining_length)
+			FixedHeader {
+				control_type: first_byte.0,
+				flags: first_byte.1,
+				remaining_length: remaining_length
+			}
 		}
 	)
 );
@@ -104,292 +137,927 @@ named!(pub length_prefixed_byte_array<&[u8], &[u8], MqttParseError>,
 	)
 );
 
+named!(two_byte_integer<&[u8], u16, MqttParseError>, fix_error!(MqttParseError, be_u16));
+
+// MQTT 5 property parser stuff
+named!(byte_property<&[u8], u8, MqttParseError>, fix_error!(MqttParseError, be_u8));
+named!(four_byte_property<&[u8], u32, MqttParseError>, fix_error!(MqttParseError, be_u32));
+
+named!(utf8_property<&[u8], String, MqttParseError>,
+	map!(length_prefixed_utf8_parser, |string: &str| string.into())
+);
+
+named!(binary_property<&[u8], Vec<u8>, MqttParseError>,
+	map!(length_prefixed_byte_array, |bytes: &[u8]| bytes.to_vec())
+);
+
+fn variable_byte_integer_property(input: &[u8]) -> IResult<&[u8], u32, MqttParseError> {
+	variable_byte_integer_parser(input, MqttParseError::InvalidVariableByteInteger)
+}
+
+// The identifier decides how the value which follows it is encoded
+fn property_parser(input: &[u8]) -> IResult<&[u8], Property, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
+
+	let identifier = match PropertyIdentifier::try_from(input[0]) {
+		Ok(identifier) => identifier,
+		Err(e) => return IResult::Error(Err::Position(ErrorKind::Custom(e), input))
+	};
+
+	let value = &input[1..];
+
+	match identifier {
+		PropertyIdentifier::PayloadFormatIndicator => map!(value, byte_property, Property::PayloadFormatIndicator),
+		PropertyIdentifier::MessageExpiryInterval => map!(value, four_byte_property, Property::MessageExpiryInterval),
+		PropertyIdentifier::ContentType => map!(value, utf8_property, Property::ContentType),
+		PropertyIdentifier::ResponseTopic => map!(value, utf8_property, Property::ResponseTopic),
+		PropertyIdentifier::CorrelationData => map!(value, binary_property, Property::CorrelationData),
+		PropertyIdentifier::SubscriptionIdentifier => map!(value, variable_byte_integer_property, Property::SubscriptionIdentifier),
+		PropertyIdentifier::SessionExpiryInterval => map!(value, four_byte_property, Property::SessionExpiryInterval),
+		PropertyIdentifier::AssignedClientIdentifier => map!(value, utf8_property, Property::AssignedClientIdentifier),
+		PropertyIdentifier::ServerKeepAlive => map!(value, two_byte_integer, Property::ServerKeepAlive),
+		PropertyIdentifier::AuthenticationMethod => map!(value, utf8_property, Property::AuthenticationMethod),
+		PropertyIdentifier::AuthenticationData => map!(value, binary_property, Property::AuthenticationData),
+		PropertyIdentifier::RequestProblemInformation => map!(value, byte_property, Property::RequestProblemInformation),
+		PropertyIdentifier::WillDelayInterval => map!(value, four_byte_property, Property::WillDelayInterval),
+		PropertyIdentifier::RequestResponseInformation => map!(value, byte_property, Property::RequestResponseInformation),
+		PropertyIdentifier::ResponseInformation => map!(value, utf8_property, Property::ResponseInformation),
+		PropertyIdentifier::ServerReference => map!(value, utf8_property, Property::ServerReference),
+		PropertyIdentifier::ReasonString => map!(value, utf8_property, Property::ReasonString),
+		PropertyIdentifier::ReceiveMaximum => map!(value, two_byte_integer, Property::ReceiveMaximum),
+		PropertyIdentifier::TopicAliasMaximum => map!(value, two_byte_integer, Property::TopicAliasMaximum),
+		PropertyIdentifier::TopicAlias => map!(value, two_byte_integer, Property::TopicAlias),
+		PropertyIdentifier::MaximumQualityOfService => map!(value, byte_property, Property::MaximumQualityOfService),
+		PropertyIdentifier::RetainAvailable => map!(value, byte_property, Property::RetainAvailable),
+		PropertyIdentifier::UserProperty => {
+			chain!(value,
+				key: utf8_property ~
+				value: utf8_property,
+				|| {
+					Property::UserProperty(key, value)
+				}
+			)
+		}
+		PropertyIdentifier::MaximumPacketSize => map!(value, four_byte_property, Property::MaximumPacketSize),
+		PropertyIdentifier::WildcardSubscriptionAvailable => map!(value, byte_property, Property::WildcardSubscriptionAvailable),
+		PropertyIdentifier::SubscriptionIdentifierAvailable => map!(value, byte_property, Property::SubscriptionIdentifierAvailable),
+		PropertyIdentifier::SharedSubscriptionAvailable => map!(value, byte_property, Property::SharedSubscriptionAvailable)
+	}
+}
+
+// The property block is prefixed with its length in bytes, rather than a count
+pub fn properties_parser(input: &[u8]) -> IResult<&[u8], Vec<Property>, MqttParseError> {
+	let (block_start, length) = match variable_byte_integer_property(input) {
+		IResult::Done(i, length) => (i, length as usize),
+		IResult::Incomplete(n) => return IResult::Incomplete(n),
+		IResult::Error(e) => return IResult::Error(e)
+	};
+
+	if block_start.len() < length {
+		return IResult::Incomplete(Needed::Size(length));
+	}
+
+	let mut properties = Vec::new();
+	let mut remaining = &block_start[..length];
+
+	while remaining.len() > 0 {
+		match property_parser(remaining) {
+			IResult::Done(i, property) => {
+				properties.push(property);
+				remaining = i;
+			}
+			// The last property runs past the length of the block
+			IResult::Incomplete(_) => return IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::MalformedPacket), remaining)),
+			IResult::Error(e) => return IResult::Error(e)
+		}
+	}
+
+	IResult::Done(&block_start[length..], properties)
+}
+
+fn reason_code_parser(input: &[u8]) -> IResult<&[u8], ReasonCode, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
+
+	match ReasonCode::try_from(input[0]) {
+		Ok(reason_code) => IResult::Done(&input[1..], reason_code),
+		Err(e) => IResult::Error(Err::Code(ErrorKind::Custom(e)))
+	}
+}
+
+// The reason code and properties at the end of some MQTT 5 packets can be left
+// off. No reason code means Success, and no properties means there aren't any.
+fn optional_reason_code_and_properties_parser(input: &[u8]) -> IResult<&[u8], (ReasonCode, Vec<Property>), MqttParseError> {
+	if input.is_empty() {
+		return IResult::Done(input, (ReasonCode::Success, Vec::new()));
+	}
+
+	let (after_reason_code, reason_code) = match field!(input, PacketField::ReasonCode, reason_code_parser) {
+		IResult::Done(i, reason_code) => (i, reason_code),
+		IResult::Incomplete(n) => return IResult::Incomplete(n),
+		IResult::Error(e) => return IResult::Error(e)
+	};
+
+	if after_reason_code.is_empty() {
+		return IResult::Done(after_reason_code, (reason_code, Vec::new()));
+	}
+
+	map!(after_reason_code, field!(PacketField::Properties, properties_parser), |properties| (reason_code, properties))
+}
+
+// Parses the properties for MQTT 5, and gives no properties for earlier versions
+fn version_properties_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Vec<Property>, MqttParseError> {
+	map!(input,
+		cond_with_error!(version.is_mqtt5(), field!(PacketField::Properties, properties_parser)),
+		|properties: Option<Vec<Property>>| properties.unwrap_or(Vec::new())
+	)
+}
+
 // Connect Variable Header parser stuff
-named!(pub connect_variable_header_parser<&[u8], VariableHeader, MqttParseError>,
+fn connect_flags_parser(input: &[u8], protocol_level: u8) -> IResult<&[u8], ConnectFlags, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
+
+	let flags_byte = input[0];
+
+	let username = flags_byte & 0b10000000 == 0b10000000;
+	let password = flags_byte & 0b01000000 == 0b01000000;
+	let will_retain = flags_byte & 0b00100000 == 0b00100000;
+	let will_qos_bits = (flags_byte & 0b00011000) >> 3;
+	let will_flag = flags_byte & 0b00000100 == 0b00000100;
+	let clean_session = flags_byte & 0b00000010 == 0b00000010;
+	let reserved = flags_byte & 0b00000001 == 0b00000001;
+
+	let error = if reserved {
+		Some(MqttParseError::ConnectReservedFlagSet)
+	} else if will_qos_bits == 3 {
+		Some(MqttParseError::InvalidWillQualityOfService)
+	} else if !will_flag && will_qos_bits != 0 {
+		Some(MqttParseError::WillQualityOfServiceWithoutWillFlag)
+	} else if !will_flag && will_retain {
+		Some(MqttParseError::WillRetainWithoutWillFlag)
+	} else if password && !username && protocol_level < 5 {
+		// MQTT 5 allows a password on its own, earlier versions need a user name with it
+		Some(MqttParseError::PasswordWithoutUsername)
+	} else {
+		None
+	};
+
+	if let Some(e) = error {
+		return IResult::Error(Err::Code(ErrorKind::Custom(e)));
+	}
+
+	match QualityOfService::try_from(will_qos_bits) {
+		Ok(will_qos) => {
+			IResult::Done(&input[1..], ConnectFlags {
+				clean_session: clean_session,
+				will_flag: will_flag,
+				will_qos: will_qos,
+				will_retain: will_retain,
+				password: password,
+				username: username
+			})
+		}
+		Err(e) => IResult::Error(Err::Code(ErrorKind::Custom(e)))
+	}
+}
+
+named!(pub connect_variable_header_parser<&[u8], ConnectVariableHeader, MqttParseError>,
 	chain!(
-		protocol_name: length_prefixed_utf8_parser ~
-		protocol_level: fix_error!(MqttParseError, be_u8) ~
-		connect_flags: fix_error!(MqttParseError, be_u8) ~
-		keep_alive: fix_error!(MqttParseError, be_u16),
+		protocol_name: field!(PacketField::ProtocolName, length_prefixed_utf8_parser) ~
+		protocol_level: field!(PacketField::ProtocolLevel, fix_error!(MqttParseError, be_u8)) ~
+		connect_flags: field!(PacketField::ConnectFlags, apply!(connect_flags_parser, protocol_level)) ~
+		keep_alive: field!(PacketField::KeepAlive, fix_error!(MqttParseError, be_u16)) ~
+		// An unsupported version is parsed as far as it can be, so the client can be told why it was refused
+		properties: apply!(version_properties_parser, ProtocolVersion::from_connect(protocol_name, protocol_level).unwrap_or(ProtocolVersion::Mqtt311)),
 		|| {
-			VariableHeader::Connect(ConnectVariableHeader {
+			ConnectVariableHeader {
 				protocol_name: protocol_name.into(),
 				protocol_level: protocol_level,
 				connect_flags: connect_flags,
-				keep_alive: keep_alive
-			})
+				keep_alive: keep_alive,
+				properties: properties
+			}
 		}
 	)
 );
 
-named!(pub connect_payload_parser<&[u8], Payload, MqttParseError>,
-	chain!(
-		protocol_name: length_prefixed_utf8_parser ~
-		protocol_level: fix_error!(MqttParseError, be_u8) ~
-		connect_flags: fix_error!(MqttParseError, be_u8) ~
-		keep_alive: fix_error!(MqttParseError, be_u16),
+// The fields present in the payload are determined by the connect flags
+// in the variable header, and must appear in this order
+pub fn connect_payload_parser<'a>(input: &'a [u8], variable_header: &ConnectVariableHeader) -> IResult<&'a [u8], ConnectPayload, MqttParseError> {
+	let connect_flags = &variable_header.connect_flags;
+	let mqtt5 = variable_header.protocol_version() == Some(ProtocolVersion::Mqtt5);
+
+	chain!(input,
+		client_id: field!(PacketField::ClientId, length_prefixed_utf8_parser) ~
+		will_properties: cond_with_error!(connect_flags.will_flag && mqtt5, field!(PacketField::WillProperties, properties_parser)) ~
+		will_topic: cond_with_error!(connect_flags.will_flag, field!(PacketField::WillTopic, topic_name_parser)) ~
+		will_message: cond_with_error!(connect_flags.will_flag, field!(PacketField::WillMessage, length_prefixed_byte_array)) ~
+		username: cond_with_error!(connect_flags.username, field!(PacketField::Username, length_prefixed_utf8_parser)) ~
+		password: cond_with_error!(connect_flags.password, field!(PacketField::Password, length_prefixed_byte_array)),
 		|| {
-			Payload::Connect(ConnectPayload {
-				client_id: "hi".into(),
-				will_topic: None,
-				will_message: None,
-				username: None,
-				password: None
-			})
+			ConnectPayload {
+				client_id: client_id.into(),
+				will_properties: will_properties.unwrap_or(Vec::new()),
+				will_topic: will_topic,
+				will_message: will_message.map(|message| message.to_vec()),
+				username: username.map(|username| username.into()),
+				password: password.map(|password| password.to_vec())
+			}
 		}
 	)
-);
+}
 
-named!(pub connect_packet_parser<&[u8], (VariableHeader, Payload), MqttParseError>,
+named!(pub connect_packet_parser<&[u8], Packet, MqttParseError>,
 	chain!(
 		variable_header: connect_variable_header_parser ~
-		payload: connect_payload_parser,
+		payload: apply!(connect_payload_parser, &variable_header),
 		|| {
-			(variable_header, payload)
+			Packet::Connect(ConnectPacket {
+				variable_header: variable_header,
+				payload: payload
+			})
 		}
 	)
 );
 
+// Connect Ack parser stuff
+fn connect_ack_flags_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], bool, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
 
-// Nom Consumer test
-
-enum ParserState {
-	ReadingFixedHeader,
-	ReadingVariableHeader,
-	ReadingPayload,
-	Invalid
+	// Bits 7-1 are reserved, bit 0 is the session present flag. In 3.1 they're all reserved.
+	match input[0] {
+		0 => IResult::Done(&input[1..], false),
+		1 if version.has_session_present() => IResult::Done(&input[1..], true),
+		_ => IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidConnectAckFlags)))
+	}
 }
 
-type MqttConsumerState = ConsumerState<FixedHeader, (), Move>;
+// Earlier versions send a return code where MQTT 5 sends a reason code
+fn connect_return_code_parser(input: &[u8]) -> IResult<&[u8], ReasonCode, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
+
+	match ConnectReturnCode::try_from(input[0]) {
+		Ok(return_code) => IResult::Done(&input[1..], return_code.into()),
+		Err(e) => IResult::Error(Err::Code(ErrorKind::Custom(e)))
+	}
+}
 
-pub struct MqttConsumer {
-	state: ParserState,
-	consumer_state: MqttConsumerState,
-	fixed_header: Option<FixedHeader>
+pub fn connect_ack_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	chain!(input,
+		session_present: field!(PacketField::ConnectAckFlags, apply!(connect_ack_flags_parser, version)) ~
+		reason_code: cond_with_error!(version.is_mqtt5(), field!(PacketField::ReasonCode, reason_code_parser)) ~
+		return_code: cond_with_error!(!version.is_mqtt5(), field!(PacketField::ReturnCode, connect_return_code_parser)) ~
+		properties: apply!(version_properties_parser, version),
+		|| {
+			Packet::ConnectAck {
+				session_present: session_present,
+				reason_code: reason_code.or(return_code).unwrap(),
+				properties: properties
+			}
+		}
+	)
 }
 
-impl MqttConsumer {
-	pub fn new() -> MqttConsumer {
-		MqttConsumer {
-			state: ParserState::ReadingFixedHeader,
-			consumer_state: ConsumerState::Continue(Move::Consume(0)),
-			fixed_header: None
+// Topic parser stuff
+// Errors point at the start of the topic, rather than the string inside it
+fn topic_name_parser(input: &[u8]) -> IResult<&[u8], TopicName, MqttParseError> {
+	match length_prefixed_utf8_parser(input) {
+		IResult::Done(i, name) => {
+			match TopicName::new(name) {
+				Ok(topic_name) => IResult::Done(i, topic_name),
+				Err(e) => IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::InvalidTopic(e)), input))
+			}
 		}
+		IResult::Incomplete(n) => IResult::Incomplete(n),
+		IResult::Error(e) => IResult::Error(e)
 	}
+}
 
-	pub fn feed_bytes(&mut self, bytes: &[u8]) -> () {
-		let mut producer = MemProducer::new(bytes, bytes.len());
-
-		println!("Got {} bytes fed to me!", bytes.len());
-
-		while let &ConsumerState::Continue(_) = producer.apply(self) {
-			// Do nothing
+fn topic_filter_parser(input: &[u8]) -> IResult<&[u8], TopicFilter, MqttParseError> {
+	match length_prefixed_utf8_parser(input) {
+		IResult::Done(i, filter) => {
+			match TopicFilter::new(filter) {
+				Ok(topic_filter) => IResult::Done(i, topic_filter),
+				Err(e) => IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::InvalidTopic(e)), input))
+			}
 		}
+		IResult::Incomplete(n) => IResult::Incomplete(n),
+		IResult::Error(e) => IResult::Error(e)
 	}
 }
 
-impl<'a> Consumer<&'a[u8], FixedHeader, (), Move> for MqttConsumer {
-	fn state(&self) -> &MqttConsumerState {
-		&self.consumer_state
+// Publish packet parser stuff
+// A QoS 1 or 2 PUBLISH must have a non-zero packet id
+fn publish_packet_id_parser(input: &[u8]) -> IResult<&[u8], u16, MqttParseError> {
+	match two_byte_integer(input) {
+		IResult::Done(_, 0) => IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidPacketId))),
+		result => result
 	}
+}
 
-	fn handle(&mut self, input: Input<&'a[u8]>) -> &MqttConsumerState {
-		// TODO - update state based on input
+// For PUBLISH packets, bit 3 of the fixed header flags is DUP, bits 2-1 are the QoS level, and bit 0 is RETAIN.
+// The payload is whatever is left of the packet body after the variable header.
+pub fn publish_packet_parser(input: &[u8], flags: u8, version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	let qos = match QualityOfService::try_from((flags & 0b0110) >> 1) {
+		Ok(qos) => qos,
+		Err(e) => return IResult::Error(Err::Code(ErrorKind::Custom(e)))
+	};
 
-		match self.state {
-			ParserState::ReadingFixedHeader => {
-				println!("In Header state!");
+	chain!(input,
+		topic_name: field!(PacketField::TopicName, topic_name_parser) ~
+		packet_id: cond_with_error!(qos != QualityOfService::AtMostOnce, field!(PacketField::PacketId, publish_packet_id_parser)) ~
+		properties: apply!(version_properties_parser, version) ~
+		payload: fix_error!(MqttParseError, rest),
+		|| {
+			Packet::Publish(PublishPacket {
+				dup: flags & 0b1000 == 0b1000,
+				qos: qos,
+				retain: flags & 0b0001 == 0b0001,
+				topic_name: topic_name,
+				packet_id: packet_id,
+				properties: properties,
+				payload: payload.to_vec()
+			})
+		}
+	)
+}
 
-				match input {
-					Input::Empty | Input::Eof(None) => {
-						self.state = ParserState::Invalid;
-						self.consumer_state = ConsumerState::Error(());
-					}
-					Input::Element(slice) | Input::Eof(Some(slice)) => {
-						match fixed_header_parser(slice) {
-							IResult::Error(_) => {
-								self.state = ParserState::Invalid;
-								self.consumer_state = ConsumerState::Error(());
-							}
-							IResult::Incomplete(n) => {
-								self.consumer_state = ConsumerState::Continue(Move::Await(n));
-							}
-							IResult::Done(_, fixed_header) => {
-								self.fixed_header = Some(fixed_header);
-								self.state = ParserState::ReadingVariableHeader;
-							}
-						}
-					}
-				}
-			}
-			ParserState::ReadingVariableHeader => {
-				println!("Fixed header is {:?}", self.fixed_header);
+// Publish response parser stuff
+// PUBACK, PUBREC, PUBREL and PUBCOMP are a packet id, followed by a reason code
+// and properties for MQTT 5
+fn publish_response_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], (u16, ReasonCode, Vec<Property>), MqttParseError> {
+	chain!(input,
+		packet_id: field!(PacketField::PacketId, two_byte_integer) ~
+		response: cond_with_error!(version.is_mqtt5(), call!(optional_reason_code_and_properties_parser)),
+		|| {
+			let (reason_code, properties) = response.unwrap_or((ReasonCode::Success, Vec::new()));
+			(packet_id, reason_code, properties)
+		}
+	)
+}
 
-				if let Some(ref fixed_header) = self.fixed_header {
-					match fixed_header.control_type {
-						ControlPacketType::Connect => {
-							println!("WE GOT A CONNECT PACKET!");
+pub fn publish_ack_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	map!(input, apply!(publish_response_parser, version), |(packet_id, reason_code, properties)| {
+		Packet::PublishAck { packet_id: packet_id, reason_code: reason_code, properties: properties }
+	})
+}
 
-						}
-						ControlPacketType::ConnectAck => {
+pub fn publish_received_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	map!(input, apply!(publish_response_parser, version), |(packet_id, reason_code, properties)| {
+		Packet::PublishReceived { packet_id: packet_id, reason_code: reason_code, properties: properties }
+	})
+}
 
-						}
-						ControlPacketType::Publish => {
+pub fn publish_release_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	map!(input, apply!(publish_response_parser, version), |(packet_id, reason_code, properties)| {
+		Packet::PublishRelease { packet_id: packet_id, reason_code: reason_code, properties: properties }
+	})
+}
 
-						}
-						ControlPacketType::PublishAck => {
+pub fn publish_complete_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	map!(input, apply!(publish_response_parser, version), |(packet_id, reason_code, properties)| {
+		Packet::PublishComplete { packet_id: packet_id, reason_code: reason_code, properties: properties }
+	})
+}
 
-						}
-						ControlPacketType::PublishReceived => {
+// Subscribe packet parser stuff
+fn requested_qos_parser(input: &[u8]) -> IResult<&[u8], QualityOfService, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
 
-						}
-						ControlPacketType::PublishRelease => {
+	let options = input[0];
 
-						}
-						ControlPacketType::PublishComplete => {
+	if options & 0b11111100 != 0 {
+		return IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::SubscriptionOptionsReservedBitsSet)));
+	}
 
-						}
-						ControlPacketType::Subscribe => {
+	match QualityOfService::try_from(options) {
+		Ok(qos) => IResult::Done(&input[1..], qos),
+		Err(e) => IResult::Error(Err::Code(ErrorKind::Custom(e)))
+	}
+}
 
-						}
-						ControlPacketType::SubscribeAck => {
+named!(subscribe_topic_parser<&[u8], SubscribeTopic, MqttParseError>,
+	chain!(
+		topic_filter: field!(PacketField::TopicFilter, topic_filter_parser) ~
+		qos: field!(PacketField::RequestedQualityOfService, requested_qos_parser),
+		|| {
+			SubscribeTopic::new(topic_filter, qos)
+		}
+	)
+);
 
-						}
-						ControlPacketType::Unsubscribe => {
+// MQTT 5 uses the rest of the requested QoS byte for more options. Bits 7-6 are
+// reserved, 5-4 are retain handling, 3 is retain as published, 2 is no local and 1-0 are the QoS.
+fn subscription_options_parser(input: &[u8]) -> IResult<&[u8], (QualityOfService, bool, bool, RetainHandling), MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
+	}
 
-						}
-						ControlPacketType::UnsubscribeAck => {
+	let options = input[0];
 
-						}
-						ControlPacketType::PingRequest => {
+	if options & 0b11000000 != 0 {
+		return IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::SubscriptionOptionsReservedBitsSet)));
+	}
 
-						}
-						ControlPacketType::PingResponse => {
+	let retain_handling = match (options & 0b00110000) >> 4 {
+		0 => RetainHandling::SendAtSubscribe,
+		1 => RetainHandling::SendAtNewSubscribe,
+		2 => RetainHandling::DoNotSend,
+		_ => return IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidRetainHandling)))
+	};
 
-						}
-						ControlPacketType::Disconnect => {
+	match QualityOfService::try_from(options & 0b00000011) {
+		Ok(qos) => {
+			let no_local = options & 0b00000100 == 0b00000100;
+			let retain_as_published = options & 0b00001000 == 0b00001000;
 
-						}
-					}
-				}
-				// println!("");
-				// println!("In Payload state!");
-			}
-			ParserState::ReadingPayload => {
-				// println!("Reading Payload!")
-			}
-			ParserState::Invalid => {
-				// println!("In Invalid state!")
-			}
+			IResult::Done(&input[1..], (qos, no_local, retain_as_published, retain_handling))
 		}
-
-		&self.consumer_state
+		Err(e) => IResult::Error(Err::Code(ErrorKind::Custom(e)))
 	}
 }
 
-// End Nom Consumer test
+named!(mqtt5_subscribe_topic_parser<&[u8], SubscribeTopic, MqttParseError>,
+	chain!(
+		topic_filter: field!(PacketField::TopicFilter, topic_filter_parser) ~
+		options: field!(PacketField::SubscriptionOptions, subscription_options_parser),
+		|| {
+			let (qos, no_local, retain_as_published, retain_handling) = options;
 
+			SubscribeTopic {
+				topic_filter: topic_filter,
+				qos: qos,
+				no_local: no_local,
+				retain_as_published: retain_as_published,
+				retain_handling: retain_handling
+			}
+		}
+	)
+);
 
-#[test]
-fn test_first_byte_parser() {
-	match first_byte_parser(&[16, 2, 143, 121, 110]) {
-		IResult::Done(i, o) => {
-			assert_eq!(i, &[2, 143, 121, 110]);
-			assert_eq!(o, FirstByteData {
-				control_type: ControlPacketType::Connect,
-				bit_0: false,
-				bit_1: false,
-				bit_2: false,
-				bit_3: false
-			})
+// Applies a parser repeatedly until the packet body is used up
+fn topic_list_parser<'a, O, F>(body: &'a [u8], parser: F) -> IResult<&'a [u8], Vec<O>, MqttParseError>
+	where F: Fn(&'a [u8]) -> IResult<&'a [u8], O, MqttParseError> {
+	let mut entries = Vec::new();
+	let mut remaining = body;
+
+	while remaining.len() > 0 {
+		match parser(remaining) {
+			IResult::Done(i, o) => {
+				entries.push(o);
+				remaining = i;
+			}
+			IResult::Incomplete(n) => return IResult::Incomplete(n),
+			IResult::Error(e) => return IResult::Error(e)
 		}
-		_ => panic!()
 	}
-}
 
-#[test]
-fn test_first_byte_parser_invalid_data() {
-	match first_byte_parser(&[0]) {
-		IResult::Done(_, _) => {
-			panic!("Expected first_byte to be invalid, but it was found to be valid")
-		}
-		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidControlType),
-		_ => panic!()
+	if entries.is_empty() {
+		return IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::EmptyTopicFilterList), remaining));
 	}
+
+	IResult::Done(remaining, entries)
 }
 
-#[test]
-fn test_length() {
-	match remaining_length_parser(&[193, 2, 143, 121, 110]) {
-		IResult::Done(i, o) => {
-			assert_eq!(i, &[143, 121, 110]);
-			assert_eq!(o, 321)
+pub fn subscribe_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	let topic_parser: fn(&[u8]) -> IResult<&[u8], SubscribeTopic, MqttParseError> = if version.is_mqtt5() {
+		mqtt5_subscribe_topic_parser
+	} else {
+		subscribe_topic_parser
+	};
+
+	chain!(input,
+		packet_id: field!(PacketField::PacketId, fix_error!(MqttParseError, be_u16)) ~
+		properties: apply!(version_properties_parser, version) ~
+		topics: apply!(topic_list_parser, topic_parser),
+		|| {
+			Packet::Subscribe {
+				packet_id: packet_id,
+				properties: properties,
+				topics: topics
+			}
 		}
-		_ => panic!()
-	}
+	)
 }
 
-#[test]
-fn test_remaining_length_needs_more() {
-	match remaining_length_parser(&[193, 255, 255]) {
-		IResult::Done(_, _) => {
-			panic!("Expected to need at least one more byte, but it was found to be valid")
-		}
-		IResult::Incomplete(Needed::Size(n)) => assert_eq!(n, 1),
-		e => panic!("{:?}", e)
+// Granted QoS values are the same in every version. Before MQTT 5 the only failure is 0x80,
+// afterwards it can be any reason code which reports an error.
+fn subscribe_return_code_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], SubscribeReturnCode, MqttParseError> {
+	if input.len() < 1 {
+		return IResult::Incomplete(Needed::Size(1));
 	}
-}
 
-#[test]
-fn test_invalid_remaining_length() {
-	match remaining_length_parser(&[193, 0xFF, 0xFF, 0xFF, 0xFF]) {
-		IResult::Done(_, _) => {
-			panic!("Expected remaining_length to be invalid, but it was found to be valid")
-		}
-		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidRemainingLength),
-		e => panic!("{:?}", e)
+	if let Ok(qos) = QualityOfService::try_from(input[0]) {
+		return IResult::Done(&input[1..], SubscribeReturnCode::Success(qos));
 	}
-}
 
-#[test]
-fn test_fixed_header_parser() {
-	let test_input = vec!(0x10, 0x1E);
-	let output = fixed_header_parser(&test_input);
+	let failure = match ReasonCode::try_from(input[0]) {
+		Ok(reason_code) if version.is_mqtt5() && reason_code.is_error() => Some(reason_code),
+		Ok(ReasonCode::UnspecifiedError) => Some(ReasonCode::UnspecifiedError),
+		_ => None
+	};
 
-	match output {
-		IResult::Done(i, o) => {
-			// assert_eq!(i, &[]);
+	match failure {
+		Some(reason_code) => IResult::Done(&input[1..], SubscribeReturnCode::Failure(reason_code)),
+		None => IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::InvalidSubscribeReturnCode), input))
+	}
+}
 
-			assert_eq!(o, FixedHeader {
-				control_type: ControlPacketType::Connect,
-				bit_0: false,
-				bit_1: false,
-				bit_2: false,
-				bit_3: false,
-				remaining_length: 30
-			})
+pub fn subscribe_ack_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	chain!(input,
+		packet_id: field!(PacketField::PacketId, fix_error!(MqttParseError, be_u16)) ~
+		properties: apply!(version_properties_parser, version) ~
+		return_codes: apply!(topic_list_parser, |i| subscribe_return_code_parser(i, version)),
+		|| {
+			Packet::SubscribeAck {
+				packet_id: packet_id,
+				properties: properties,
+				return_codes: return_codes
+			}
 		}
-		IResult::Incomplete(e) => panic!("Input was incomplete: {:?}", e),
-		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidRemainingLength),
-		IResult::Error(e) => panic!("Error: {:?}", e)
-	}
+	)
 }
 
-#[test]
-fn test_utf8_parser_valid() {
-	// The UTF-8 sequence "A"
-	let test_input = vec!(0x00, 0x01, 0x41);
+// Unsubscribe packet parser stuff
+named!(unsubscribe_topic_parser<&[u8], TopicFilter, MqttParseError>,
+	field!(PacketField::TopicFilter, topic_filter_parser)
+);
 
-	match length_prefixed_utf8_parser(&test_input) {
-		IResult::Done(i, o) => {
-			assert_eq!(o, "A");
+pub fn unsubscribe_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	chain!(input,
+		packet_id: field!(PacketField::PacketId, fix_error!(MqttParseError, be_u16)) ~
+		properties: apply!(version_properties_parser, version) ~
+		topic_filters: apply!(topic_list_parser, unsubscribe_topic_parser),
+		|| {
+			Packet::Unsubscribe {
+				packet_id: packet_id,
+				properties: properties,
+				topic_filters: topic_filters
+			}
 		}
-		e => panic!("{:?}", e)
-	}
+	)
 }
 
-#[test]
-fn test_utf8_parser_invalid() {
+// Before MQTT 5 this is just the packet id
+pub fn unsubscribe_ack_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	chain!(input,
+		packet_id: field!(PacketField::PacketId, fix_error!(MqttParseError, be_u16)) ~
+		properties: apply!(version_properties_parser, version) ~
+		reason_codes: cond_with_error!(version.is_mqtt5(), apply!(topic_list_parser, |i| field!(i, PacketField::ReasonCode, reason_code_parser))),
+		|| {
+			Packet::UnsubscribeAck {
+				packet_id: packet_id,
+				properties: properties,
+				reason_codes: reason_codes.unwrap_or(Vec::new())
+			}
+		}
+	)
+}
+
+// Disconnect and auth parser stuff
+pub fn disconnect_packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	map!(input,
+		cond_with_error!(version.is_mqtt5(), call!(optional_reason_code_and_properties_parser)),
+		|response: Option<(ReasonCode, Vec<Property>)>| {
+			let (reason_code, properties) = response.unwrap_or((ReasonCode::Success, Vec::new()));
+			Packet::Disconnect { reason_code: reason_code, properties: properties }
+		}
+	)
+}
+
+named!(pub auth_packet_parser<&[u8], Packet, MqttParseError>,
+	map!(optional_reason_code_and_properties_parser, |(reason_code, properties)| {
+		Packet::Auth { reason_code: reason_code, properties: properties }
+	})
+);
+
+// Parses a packet body that has already been split off using the remaining length.
+// Every byte of the body must be used, and running out of bytes part way through
+// a field means the remaining length was wrong.
+// CONNECT says which version it uses itself, every other packet is parsed with
+// the version the connection agreed on.
+fn body_parser<'a>(body: &'a [u8], fixed_header: &FixedHeader, version: ProtocolVersion) -> IResult<&'a [u8], Packet, MqttParseError> {
+	let flags = fixed_header.flags;
+
+	let result = match fixed_header.control_type {
+		ControlPacketType::Connect => connect_packet_parser(body),
+		ControlPacketType::ConnectAck => connect_ack_packet_parser(body, version),
+		ControlPacketType::Publish => publish_packet_parser(body, flags, version),
+		ControlPacketType::PublishAck => publish_ack_packet_parser(body, version),
+		ControlPacketType::PublishReceived => publish_received_packet_parser(body, version),
+		ControlPacketType::PublishRelease => publish_release_packet_parser(body, version),
+		ControlPacketType::PublishComplete => publish_complete_packet_parser(body, version),
+		ControlPacketType::Subscribe => subscribe_packet_parser(body, version),
+		ControlPacketType::SubscribeAck => subscribe_ack_packet_parser(body, version),
+		ControlPacketType::Unsubscribe => unsubscribe_packet_parser(body, version),
+		ControlPacketType::UnsubscribeAck => unsubscribe_ack_packet_parser(body, version),
+		ControlPacketType::PingRequest => IResult::Done(body, Packet::PingRequest),
+		ControlPacketType::PingResponse => IResult::Done(body, Packet::PingResponse),
+		ControlPacketType::Disconnect => disconnect_packet_parser(body, version),
+		// Type 15 is reserved before MQTT 5
+		ControlPacketType::Auth if version.is_mqtt5() => auth_packet_parser(body),
+		ControlPacketType::Auth => IResult::Error(Err::Code(ErrorKind::Custom(MqttParseError::InvalidControlType)))
+	};
+
+	match result {
+		IResult::Done(remaining, packet) => {
+			if remaining.is_empty() {
+				IResult::Done(remaining, packet)
+			} else {
+				// Points at the first byte which wasn't part of the packet
+				IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::InvalidRemainingLength), remaining))
+			}
+		}
+		IResult::Incomplete(_) => {
+			// Points at the end of the body, where we ran out of bytes
+			IResult::Error(Err::Position(ErrorKind::Custom(MqttParseError::InvalidRemainingLength), &body[body.len()..]))
+		}
+		IResult::Error(e) => IResult::Error(e)
+	}
+}
+
+// Parses the packet body following a fixed header which has already been read
+pub fn packet_body_parser<'a>(input: &'a [u8], fixed_header: &FixedHeader, version: ProtocolVersion) -> IResult<&'a [u8], Packet, MqttParseError> {
+	let remaining_length = fixed_header.remaining_length as usize;
+
+	if input.len() < remaining_length {
+		return IResult::Incomplete(Needed::Size(remaining_length));
+	}
+
+	match body_parser(&input[..remaining_length], fixed_header, version) {
+		IResult::Done(_, packet) => IResult::Done(&input[remaining_length..], packet),
+		IResult::Incomplete(n) => IResult::Incomplete(n),
+		IResult::Error(e) => IResult::Error(e)
+	}
+}
+
+pub fn packet_parser(input: &[u8], version: ProtocolVersion) -> IResult<&[u8], Packet, MqttParseError> {
+	chain!(input,
+		fixed_header: fixed_header_parser ~
+		packet: apply!(packet_body_parser, &fixed_header, version),
+		|| {
+			packet
+		}
+	)
+}
+
+
+// Pulls the MqttParseError out of a nom error, looking through any errors which
+// were wrapped around it by the combinators. The field comes from the field! tag,
+// and the offset from the innermost error which recorded its position.
+pub fn decode_error(error: Err<&[u8], MqttParseError>, packet: &[u8], control_type: Option<ControlPacketType>) -> DecodeError {
+	let mut kind = None;
+	let mut field = None;
+	let mut position = None;
+	let mut next = Some(error);
+
+	while let Some(error) = next.take() {
+		let (error_kind, error_position, inner) = match error {
+			Err::Code(error_kind) => (error_kind, None, None),
+			Err::Node(error_kind, inner) => (error_kind, None, Some(inner)),
+			Err::Position(error_kind, p) => (error_kind, Some(p), None),
+			Err::NodePosition(error_kind, p, inner) => (error_kind, Some(p), Some(inner))
+		};
+
+		match error_kind {
+			ErrorKind::Custom(MqttParseError::InvalidField(f)) => {
+				field = field.or(Some(f));
+			}
+			ErrorKind::Custom(e) => {
+				if kind.is_none() {
+					kind = Some(e);
+				}
+			}
+			_ => {}
+		}
+
+		if error_position.is_some() {
+			position = error_position;
+		}
+
+		next = inner.map(|inner| *inner);
+	}
+
+	DecodeError {
+		kind: kind.unwrap_or(MqttParseError::MalformedPacket),
+		control_type: control_type,
+		field: field,
+		// Every position is a suffix of the packet, so its length tells us how far in it is
+		offset: position.map(|p| packet.len() - p.len()).unwrap_or(0)
+	}
+}
+
+// Buffers bytes from the socket until they make up complete packets. Bytes from
+// a packet which is split across reads are kept until the rest of it arrives.
+pub struct MqttConsumer {
+	buffer: Vec<u8>,
+	max_packet_size: usize,
+	// Taken from the CONNECT packet, and used to parse everything after it
+	protocol_version: ProtocolVersion
+}
+
+impl MqttConsumer {
+	pub fn new(max_packet_size: usize) -> MqttConsumer {
+		MqttConsumer {
+			buffer: Vec::new(),
+			max_packet_size: max_packet_size,
+			protocol_version: ProtocolVersion::Mqtt311
+		}
+	}
+
+	// Returns every packet which was completed by these bytes, in the order they were sent
+	pub fn feed_bytes(&mut self, bytes: &[u8]) -> Result<Vec<Packet>, DecodeError> {
+		self.buffer.extend_from_slice(bytes);
+
+		let mut packets = Vec::new();
+		let mut consumed = 0;
+
+		loop {
+			let input = &self.buffer[consumed..];
+
+			let (after_fixed_header, fixed_header) = match fixed_header_parser(input) {
+				IResult::Done(rest, fixed_header) => (rest, fixed_header),
+				IResult::Incomplete(_) => break,
+				IResult::Error(e) => {
+					// The control type is still worth reporting if only the flags were bad
+					let control_type = ControlPacketType::try_from(input[0] >> 4).ok();
+					return Err(decode_error(e, input, control_type));
+				}
+			};
+
+			// Check the declared size as soon as we know it, so an oversized packet
+			// is refused before we wait around buffering its body
+			let fixed_header_length = input.len() - after_fixed_header.len();
+			let packet_size = fixed_header_length + fixed_header.remaining_length as usize;
+
+			if packet_size > self.max_packet_size {
+				return Err(DecodeError {
+					kind: MqttParseError::PacketTooLarge,
+					control_type: Some(fixed_header.control_type),
+					field: None,
+					offset: 0
+				});
+			}
+
+			match packet_body_parser(after_fixed_header, &fixed_header, self.protocol_version) {
+				IResult::Done(rest, packet) => {
+					consumed = self.buffer.len() - rest.len();
+
+					// Packets after the CONNECT may already be buffered, so switch versions straight away
+					if let Packet::Connect(ref connect) = packet {
+						if let Some(version) = connect.variable_header.protocol_version() {
+							self.protocol_version = version;
+						}
+					}
+
+					packets.push(packet);
+				}
+				IResult::Incomplete(_) => break,
+				IResult::Error(e) => return Err(decode_error(e, input, Some(fixed_header.control_type)))
+			}
+		}
+
+		self.buffer.drain(..consumed);
+
+		Ok(packets)
+	}
+
+	pub fn buffered_len(&self) -> usize {
+		self.buffer.len()
+	}
+}
+
+
+#[test]
+fn test_first_byte_parser() {
+	match first_byte_parser(&[16, 2, 143, 121, 110]) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[2, 143, 121, 110]);
+			assert_eq!(o, (ControlPacketType::Connect, 0))
+		}
+		_ => panic!()
+	}
+}
+
+#[test]
+fn test_first_byte_parser_invalid_data() {
+	match first_byte_parser(&[0]) {
+		IResult::Done(_, _) => {
+			panic!("Expected first_byte to be invalid, but it was found to be valid")
+		}
+		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidControlType),
+		_ => panic!()
+	}
+}
+
+#[test]
+fn test_first_byte_parser_flags() {
+	let valid = vec!(
+		(0x10, ControlPacketType::Connect),
+		(0x3F, ControlPacketType::Publish),
+		(0x62, ControlPacketType::PublishRelease),
+		(0x82, ControlPacketType::Subscribe),
+		(0xA2, ControlPacketType::Unsubscribe),
+		(0xE0, ControlPacketType::Disconnect)
+	);
+
+	for (first_byte, control_type) in valid {
+		match first_byte_parser(&[first_byte]) {
+			IResult::Done(_, o) => assert_eq!(o, (control_type, first_byte & 0x0F)),
+			e => panic!("{:?}", e)
+		}
+	}
+
+	let invalid = vec!(0x11, 0x28, 0x41, 0x60, 0x63, 0x80, 0x92, 0xA0, 0xC2, 0xD1, 0xE4);
+
+	for first_byte in invalid {
+		match first_byte_parser(&[first_byte]) {
+			IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidFixedHeaderFlags),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_length() {
+	match remaining_length_parser(&[193, 2, 143, 121, 110]) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[143, 121, 110]);
+			assert_eq!(o, 321)
+		}
+		_ => panic!()
+	}
+}
+
+#[test]
+fn test_remaining_length_needs_more() {
+	match remaining_length_parser(&[193, 255, 255]) {
+		IResult::Done(_, _) => {
+			panic!("Expected to need at least one more byte, but it was found to be valid")
+		}
+		IResult::Incomplete(Needed::Size(n)) => assert_eq!(n, 1),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_invalid_remaining_length() {
+	match remaining_length_parser(&[193, 0xFF, 0xFF, 0xFF, 0xFF]) {
+		IResult::Done(_, _) => {
+			panic!("Expected remaining_length to be invalid, but it was found to be valid")
+		}
+		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidRemainingLength),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_fixed_header_parser() {
+	let test_input = vec!(0x10, 0x1E);
+	let output = fixed_header_parser(&test_input);
+
+	match output {
+		IResult::Done(i, o) => {
+			// assert_eq!(i, &[]);
+
+			assert_eq!(o, FixedHeader {
+				control_type: ControlPacketType::Connect,
+				flags: 0,
+				remaining_length: 30
+			})
+		}
+		IResult::Incomplete(e) => panic!("Input was incomplete: {:?}", e),
+		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::InvalidRemainingLength),
+		IResult::Error(e) => panic!("Error: {:?}", e)
+	}
+}
+
+#[test]
+fn test_utf8_parser_valid() {
+	// The UTF-8 sequence "A"
+	let test_input = vec!(0x00, 0x01, 0x41);
+
+	match length_prefixed_utf8_parser(&test_input) {
+		IResult::Done(i, o) => {
+			assert_eq!(o, "A");
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_utf8_parser_invalid() {
 	// 0xDFFF is an invalid UTF-8 sequence
 	let test_input = vec!(0x00, 0x02, 0xDF, 0xFF);
 
@@ -413,13 +1081,909 @@ fn test_connect_variable_header_parser() {
 
 	match connect_variable_header_parser(&test_input) {
 		IResult::Done(i, o) => {
-			assert_eq!(o, VariableHeader::Connect(ConnectVariableHeader {
+			assert_eq!(o, ConnectVariableHeader {
 				protocol_name: "MQTT".into(),
 				protocol_level: 4,
-				connect_flags: 0,
-				keep_alive: 60
-			}));
+				connect_flags: ConnectFlags {
+					clean_session: false,
+					will_flag: false,
+					will_qos: QualityOfService::AtMostOnce,
+					will_retain: false,
+					password: false,
+					username: false
+				},
+				keep_alive: 60,
+				properties: Vec::new()
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_variable_header_parser_mqtt_31() {
+	let test_input = vec!(
+		0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', // Protocol Name
+		0x03, // Protocol Level
+		0x02, // Connect Flags
+		0x00, 0x0A // Keep alive time - 10 seconds
+	);
+
+	match connect_variable_header_parser(&test_input) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o.protocol_name, "MQIsdp");
+			assert_eq!(o.protocol_level, 3);
+			assert_eq!(o.keep_alive, 10);
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_flags_parser() {
+	match connect_flags_parser(&[0b11110110], 4) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, ConnectFlags {
+				clean_session: true,
+				will_flag: true,
+				will_qos: QualityOfService::ExactlyOnce,
+				will_retain: true,
+				password: true,
+				username: true
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_flags_parser_invalid() {
+	let cases = vec!(
+		(0b00000001, MqttParseError::ConnectReservedFlagSet),
+		(0b00011100, MqttParseError::InvalidWillQualityOfService),
+		(0b00001000, MqttParseError::WillQualityOfServiceWithoutWillFlag),
+		(0b00100000, MqttParseError::WillRetainWithoutWillFlag),
+		(0b01000000, MqttParseError::PasswordWithoutUsername)
+	);
+
+	for (flags, expected) in cases {
+		match connect_flags_parser(&[flags], 4) {
+			IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, expected),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_connect_variable_header_parser_mqtt5_password_only() {
+	let test_input = vec!(
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x05, // Protocol Level
+		0b01000010, // Connect Flags - password without user name
+		0x00, 0x3C, // Keep alive time - 60 seconds
+		0x00 // Properties length
+	);
+
+	match connect_variable_header_parser(&test_input) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert!(o.connect_flags.password);
+			assert!(!o.connect_flags.username);
+		}
+		e => panic!("{:?}", e)
+	}
+
+	// The same flags are still refused for MQTT 3.1.1
+	match connect_flags_parser(&[0b01000010], 4) {
+		IResult::Error(Err::Code(ErrorKind::Custom(e))) => assert_eq!(e, MqttParseError::PasswordWithoutUsername),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[cfg(test)]
+fn test_variable_header(connect_flags: ConnectFlags) -> ConnectVariableHeader {
+	ConnectVariableHeader {
+		protocol_name: "MQTT".into(),
+		protocol_level: 4,
+		connect_flags: connect_flags,
+		keep_alive: 60,
+		properties: Vec::new()
+	}
+}
+
+#[test]
+fn test_connect_payload_parser_client_id_only() {
+	let test_input = vec!(
+		0x00, 0x03, b'a', b'b', b'c' // Client ID
+	);
+
+	let connect_flags = ConnectFlags {
+		clean_session: true,
+		will_flag: false,
+		will_qos: QualityOfService::AtMostOnce,
+		will_retain: false,
+		password: false,
+		username: false
+	};
+
+	match connect_payload_parser(&test_input, &test_variable_header(connect_flags)) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, ConnectPayload {
+				client_id: "abc".into(),
+				will_properties: Vec::new(),
+				will_topic: None,
+				will_message: None,
+				username: None,
+				password: None
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_payload_parser_all_fields() {
+	let test_input = vec!(
+		0x00, 0x03, b'a', b'b', b'c', // Client ID
+		0x00, 0x04, b'w', b'i', b'l', b'l', // Will Topic
+		0x00, 0x02, 0xDE, 0xAD, // Will Message
+		0x00, 0x04, b'u', b's', b'e', b'r', // Username
+		0x00, 0x03, 0x01, 0x02, 0x03 // Password
+	);
+
+	let connect_flags = ConnectFlags {
+		clean_session: false,
+		will_flag: true,
+		will_qos: QualityOfService::AtMostOnce,
+		will_retain: false,
+		password: true,
+		username: true
+	};
+
+	match connect_payload_parser(&test_input, &test_variable_header(connect_flags)) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, ConnectPayload {
+				client_id: "abc".into(),
+				will_properties: Vec::new(),
+				will_topic: Some(TopicName::new("will").unwrap()),
+				will_message: Some(vec!(0xDE, 0xAD)),
+				username: Some("user".into()),
+				password: Some(vec!(0x01, 0x02, 0x03))
+			});
 		}
 		e => panic!("{:?}", e)
 	}
 }
+
+#[test]
+fn test_connect_payload_parser_missing_username() {
+	let test_input = vec!(
+		0x00, 0x03, b'a', b'b', b'c' // Client ID
+	);
+
+	let connect_flags = ConnectFlags {
+		clean_session: false,
+		will_flag: false,
+		will_qos: QualityOfService::AtMostOnce,
+		will_retain: false,
+		password: false,
+		username: true
+	};
+
+	match connect_payload_parser(&test_input, &test_variable_header(connect_flags)) {
+		IResult::Incomplete(_) => assert!(true),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_payload_parser_invalid_will_topic() {
+	let test_input = vec!(
+		0x00, 0x03, b'a', b'b', b'c', // Client ID
+		0x00, 0x03, b'a', b'/', b'#', // Will Topic
+		0x00, 0x00 // Will Message
+	);
+
+	let connect_flags = ConnectFlags {
+		clean_session: false,
+		will_flag: true,
+		will_qos: QualityOfService::AtMostOnce,
+		will_retain: false,
+		password: false,
+		username: false
+	};
+
+	match connect_payload_parser(&test_input, &test_variable_header(connect_flags)) {
+		IResult::Error(e) => {
+			let error = decode_error(e, &test_input, None);
+			assert_eq!(error.kind, MqttParseError::InvalidTopic(TopicError::WildcardInTopicName));
+			assert_eq!(error.field, Some(PacketField::WillTopic));
+			assert_eq!(error.offset, 5);
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_packet_parser() {
+	let test_input = vec!(
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x04, // Protocol Level
+		0b10000010, // Connect Flags - username, clean session
+		0x00, 0x3C, // Keep alive time - 60 seconds
+		0x00, 0x02, b'i', b'd', // Client ID
+		0x00, 0x04, b'u', b's', b'e', b'r' // Username
+	);
+
+	match connect_packet_parser(&test_input) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, Packet::Connect(ConnectPacket {
+				variable_header: ConnectVariableHeader {
+					protocol_name: "MQTT".into(),
+					protocol_level: 4,
+					connect_flags: ConnectFlags {
+						clean_session: true,
+						will_flag: false,
+						will_qos: QualityOfService::AtMostOnce,
+						will_retain: false,
+						password: false,
+						username: true
+					},
+					keep_alive: 60,
+					properties: Vec::new()
+				},
+				payload: ConnectPayload {
+					client_id: "id".into(),
+					will_properties: Vec::new(),
+					will_topic: None,
+					will_message: None,
+					username: Some("user".into()),
+					password: None
+				}
+			}));
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_properties_parser() {
+	let test_input = vec!(
+		0x0F, // Property Length
+		0x01, 0x01, // Payload Format Indicator
+		0x0B, 0x80, 0x01, // Subscription Identifier - 128
+		0x21, 0x00, 0x0A, // Receive Maximum
+		0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', // User Property
+		0x11, 0x00, 0x00, // Start of a Session Expiry Interval, which isn't part of the block
+	);
+
+	match properties_parser(&test_input) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[0x11, 0x00, 0x00]);
+			assert_eq!(o, vec!(
+				Property::PayloadFormatIndicator(1),
+				Property::SubscriptionIdentifier(128),
+				Property::ReceiveMaximum(10),
+				Property::UserProperty("k".into(), "v".into())
+			));
+		}
+		e => panic!("{:?}", e)
+	}
+
+	match properties_parser(&[0x00]) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, vec!());
+		}
+		e => panic!("{:?}", e)
+	}
+
+	match properties_parser(&[0x02, 0x04, 0x00]) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &[0x02, 0x04, 0x00], None).kind, MqttParseError::InvalidPropertyIdentifier),
+		e => panic!("{:?}", e)
+	}
+
+	// The Receive Maximum needs two bytes, but the block ends after one
+	match properties_parser(&[0x02, 0x21, 0x00, 0x0A]) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &[0x02, 0x21, 0x00, 0x0A], None).kind, MqttParseError::MalformedPacket),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_mqtt5_connect_packet_parser() {
+	let test_input = vec!(
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x05, // Protocol Level
+		0b00000110, // Connect Flags - will, clean start
+		0x00, 0x3C, // Keep alive time - 60 seconds
+		0x05, 0x11, 0x00, 0x00, 0x00, 0x78, // Properties - Session Expiry Interval
+		0x00, 0x02, b'i', b'd', // Client ID
+		0x02, 0x01, 0x01, // Will Properties - Payload Format Indicator
+		0x00, 0x01, b'w', // Will Topic
+		0x00, 0x01, b'm' // Will Message
+	);
+
+	match connect_packet_parser(&test_input) {
+		IResult::Done(i, Packet::Connect(o)) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o.variable_header.protocol_version(), Some(ProtocolVersion::Mqtt5));
+			assert_eq!(o.variable_header.properties, vec!(Property::SessionExpiryInterval(120)));
+			assert_eq!(o.payload.client_id, "id");
+			assert_eq!(o.payload.will_properties, vec!(Property::PayloadFormatIndicator(1)));
+			assert_eq!(o.payload.will_topic, Some(TopicName::new("w").unwrap()));
+			assert_eq!(o.payload.will_message, Some(vec!(b'm')));
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_mqtt5_publish_response_parser() {
+	let version = ProtocolVersion::Mqtt5;
+
+	let cases = vec!(
+		// The reason code and properties can both be left off
+		(vec!(0x40, 0x02, 0x00, 0x01), ReasonCode::Success, vec!()),
+		(vec!(0x40, 0x03, 0x00, 0x01, 0x10), ReasonCode::NoMatchingSubscribers, vec!()),
+		(vec!(0x40, 0x08, 0x00, 0x01, 0x97, 0x04, 0x1F, 0x00, 0x01, b'x'), ReasonCode::QuotaExceeded, vec!(Property::ReasonString("x".into())))
+	);
+
+	for (test_input, reason_code, properties) in cases {
+		match packet_parser(&test_input, version) {
+			IResult::Done(_, o) => {
+				assert_eq!(o, Packet::PublishAck { packet_id: 1, reason_code: reason_code, properties: properties });
+			}
+			e => panic!("{:?}", e)
+		}
+	}
+
+	let test_input = vec!(0x40, 0x03, 0x00, 0x01, 0x03);
+
+	match packet_parser(&test_input, version) {
+		IResult::Error(e) => {
+			let error = decode_error(e, &test_input, None);
+			assert_eq!(error.kind, MqttParseError::InvalidReasonCode);
+			assert_eq!(error.field, Some(PacketField::ReasonCode));
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_mqtt5_subscribe_packet_parser() {
+	let test_input = vec!(
+		0x82, 0x09, // Fixed header
+		0x00, 0x01, // Packet ID
+		0x02, 0x0B, 0x05, // Properties - Subscription Identifier
+		0x00, 0x01, b'a', // Topic Filter
+		0b00101101 // Subscription Options - don't send retained, retain as published, no local, QoS 1
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt5) {
+		IResult::Done(_, o) => {
+			assert_eq!(o, Packet::Subscribe {
+				packet_id: 1,
+				properties: vec!(Property::SubscriptionIdentifier(5)),
+				topics: vec!(
+					SubscribeTopic {
+						topic_filter: TopicFilter::new("a").unwrap(),
+						qos: QualityOfService::AtLeastOnce,
+						no_local: true,
+						retain_as_published: true,
+						retain_handling: RetainHandling::DoNotSend
+					}
+				)
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+
+	let cases = vec!(
+		(vec!(0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x40), MqttParseError::SubscriptionOptionsReservedBitsSet),
+		(vec!(0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, b'a', 0x30), MqttParseError::InvalidRetainHandling)
+	);
+
+	for (test_input, expected) in cases {
+		match packet_parser(&test_input, ProtocolVersion::Mqtt5) {
+			IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, expected),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_auth_packet_parser() {
+	let test_input = vec!(0xF0, 0x00);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt5) {
+		IResult::Done(_, o) => assert_eq!(o, Packet::Auth { reason_code: ReasonCode::Success, properties: vec!() }),
+		e => panic!("{:?}", e)
+	}
+
+	// Type 15 is reserved before MQTT 5
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidControlType),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_ack_packet_parser() {
+	match packet_parser(&[0x20, 0x02, 0x01, 0x05], ProtocolVersion::Mqtt311) {
+		IResult::Done(_, o) => {
+			assert_eq!(o, Packet::ConnectAck {
+				session_present: true,
+				reason_code: ReasonCode::NotAuthorized, properties: Vec::new()
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+
+	let test_input = vec!(0x20, 0x02, 0x02, 0x00);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidConnectAckFlags),
+		e => panic!("{:?}", e)
+	}
+
+	let test_input = vec!(0x20, 0x02, 0x00, 0x06);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidConnectReturnCode),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_connect_ack_packet_parser_mqtt31() {
+	match packet_parser(&[0x20, 0x02, 0x00, 0x02], ProtocolVersion::Mqtt31) {
+		IResult::Done(_, o) => {
+			assert_eq!(o, Packet::ConnectAck {
+				session_present: false,
+				reason_code: ReasonCode::ClientIdentifierNotValid, properties: Vec::new()
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+
+	// There's no session present flag in 3.1
+	let test_input = vec!(0x20, 0x02, 0x01, 0x00);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt31) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidConnectAckFlags),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_publish_packet_parser_qos_0() {
+	let test_input = vec!(
+		0x30, 0x09, // Fixed header - QoS 0
+		0x00, 0x03, b'a', b'/', b'b', // Topic Name
+		b'h', b'e', b'y', b'!' // Payload
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, Packet::Publish(PublishPacket {
+				dup: false,
+				qos: QualityOfService::AtMostOnce,
+				retain: false,
+				topic_name: TopicName::new("a/b").unwrap(),
+				packet_id: None,
+				properties: Vec::new(),
+				payload: b"hey!".to_vec()
+			}));
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_publish_packet_parser_qos_1() {
+	let test_input = vec!(
+		0x3B, 0x08, // Fixed header - DUP, QoS 1, RETAIN
+		0x00, 0x03, b'a', b'/', b'b', // Topic Name
+		0x00, 0x0A, // Packet ID
+		0xFF, // Payload
+		0xE0 // Start of the next packet
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[0xE0]);
+			assert_eq!(o, Packet::Publish(PublishPacket {
+				dup: true,
+				qos: QualityOfService::AtLeastOnce,
+				retain: true,
+				topic_name: TopicName::new("a/b").unwrap(),
+				packet_id: Some(10),
+				properties: Vec::new(),
+				payload: vec!(0xFF)
+			}));
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_publish_packet_parser_invalid_qos() {
+	let test_input = vec!(
+		0x36, 0x05, // Fixed header - QoS 3
+		0x00, 0x03, b'a', b'/', b'b' // Topic Name
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidQualityOfService),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_publish_packet_parser_invalid_packet_id() {
+	let test_input = vec!(
+		0x32, 0x07, // Fixed header - QoS 1
+		0x00, 0x03, b'a', b'/', b'b', // Topic Name
+		0x00, 0x00 // Packet Identifier
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => {
+			let error = decode_error(e, &test_input, None);
+			assert_eq!(error.kind, MqttParseError::InvalidPacketId);
+			assert_eq!(error.field, Some(PacketField::PacketId));
+			assert_eq!(error.offset, 7);
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_publish_packet_parser_invalid_topic() {
+	let cases = vec!(
+		(vec!(0x30, 0x02, 0x00, 0x00), TopicError::Empty),
+		(vec!(0x30, 0x05, 0x00, 0x03, b'a', b'/', b'#'), TopicError::WildcardInTopicName),
+		(vec!(0x30, 0x05, 0x00, 0x03, b'a', 0x00, b'b'), TopicError::NullCharacter)
+	);
+
+	for (test_input, expected) in cases {
+		match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+			IResult::Error(e) => {
+				let error = decode_error(e, &test_input, None);
+				assert_eq!(error.kind, MqttParseError::InvalidTopic(expected));
+				assert_eq!(error.field, Some(PacketField::TopicName));
+				assert_eq!(error.offset, 2);
+			}
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_packet_id_packet_parsers() {
+	let cases = vec!(
+		(vec!(0x40, 0x02, 0x00, 0x01), Packet::PublishAck { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() }),
+		(vec!(0x50, 0x02, 0x00, 0x02), Packet::PublishReceived { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() }),
+		(vec!(0x62, 0x02, 0x00, 0x03), Packet::PublishRelease { packet_id: 3, reason_code: ReasonCode::Success, properties: Vec::new() }),
+		(vec!(0x70, 0x02, 0x00, 0x04), Packet::PublishComplete { packet_id: 4, reason_code: ReasonCode::Success, properties: Vec::new() }),
+		(vec!(0xB0, 0x02, 0x00, 0x05), Packet::UnsubscribeAck { packet_id: 5, properties: Vec::new(), reason_codes: Vec::new() })
+	);
+
+	for (test_input, expected) in cases {
+		match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+			IResult::Done(_, o) => assert_eq!(o, expected),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_packet_parser_wrong_remaining_length() {
+	// A PUBACK with a remaining length of 3 has a stray byte after the packet ID
+	let test_input = vec!(0x40, 0x03, 0x00, 0x01, 0x00);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidRemainingLength),
+		e => panic!("{:?}", e)
+	}
+
+	// A PUBACK with a remaining length of 1 is too short to hold the packet ID
+	let test_input = vec!(0x40, 0x01, 0x00, 0x01);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidRemainingLength),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_ping_request_with_body() {
+	// PINGREQ has no variable header or payload, so its remaining length must be 0
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	assert_eq!(consumer.feed_bytes(&[0xC0, 0x01, 0x00]), Err(DecodeError {
+		kind: MqttParseError::InvalidRemainingLength,
+		control_type: Some(ControlPacketType::PingRequest),
+		field: None,
+		offset: 2
+	}));
+}
+
+#[test]
+fn test_packet_parser_needs_more() {
+	match packet_parser(&[0x40, 0x02, 0x00], ProtocolVersion::Mqtt311) {
+		IResult::Incomplete(_) => assert!(true),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_subscribe_packet_parser() {
+	let test_input = vec!(
+		0x82, 0x0E, // Fixed header
+		0x00, 0x01, // Packet ID
+		0x00, 0x03, b'a', b'/', b'+', // Topic Filter
+		0x01, // Requested QoS
+		0x00, 0x03, b'b', b'/', b'#', // Topic Filter
+		0x02 // Requested QoS
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, Packet::Subscribe {
+				packet_id: 1,
+				properties: Vec::new(),
+				topics: vec!(
+					SubscribeTopic::new(TopicFilter::new("a/+").unwrap(), QualityOfService::AtLeastOnce),
+					SubscribeTopic::new(TopicFilter::new("b/#").unwrap(), QualityOfService::ExactlyOnce)
+				)
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_subscribe_packet_parser_invalid() {
+	let cases = vec!(
+		// Reserved fixed header bits are 0000
+		(vec!(0x80, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x00), MqttParseError::InvalidFixedHeaderFlags),
+		// No topic filters
+		(vec!(0x82, 0x02, 0x00, 0x01), MqttParseError::EmptyTopicFilterList),
+		// Reserved bits of the requested QoS are set
+		(vec!(0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x04), MqttParseError::SubscriptionOptionsReservedBitsSet),
+		// Requested QoS is 3
+		(vec!(0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x03), MqttParseError::InvalidQualityOfService),
+		// Remaining length stops part way through a topic filter
+		(vec!(0x82, 0x05, 0x00, 0x01, 0x00, 0x02, b'a', b'b', 0x00), MqttParseError::InvalidRemainingLength),
+		// Wildcards which don't take up a whole level
+		(vec!(0x82, 0x07, 0x00, 0x01, 0x00, 0x02, b'a', b'+', 0x00), MqttParseError::InvalidTopic(TopicError::MisplacedSingleLevelWildcard)),
+		(vec!(0x82, 0x09, 0x00, 0x01, 0x00, 0x04, b'#', b'/', b'a', b'/', 0x00), MqttParseError::InvalidTopic(TopicError::MisplacedMultiLevelWildcard)),
+		// System topics can be subscribed to, but the filter still has to be valid
+		(vec!(0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00), MqttParseError::InvalidTopic(TopicError::NullCharacter))
+	);
+
+	for (test_input, expected) in cases {
+		match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+			IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, expected),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_subscribe_ack_packet_parser() {
+	match packet_parser(&[0x90, 0x05, 0x00, 0x0A, 0x00, 0x02, 0x80], ProtocolVersion::Mqtt311) {
+		IResult::Done(_, o) => {
+			assert_eq!(o, Packet::SubscribeAck {
+				packet_id: 10,
+				properties: Vec::new(),
+				return_codes: vec!(
+					SubscribeReturnCode::Success(QualityOfService::AtMostOnce),
+					SubscribeReturnCode::Success(QualityOfService::ExactlyOnce),
+					SubscribeReturnCode::Failure(ReasonCode::UnspecifiedError)
+				)
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+
+	let test_input = vec!(0x90, 0x03, 0x00, 0x0A, 0x03);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, MqttParseError::InvalidSubscribeReturnCode),
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_unsubscribe_packet_parser() {
+	let test_input = vec!(
+		0xA2, 0x0C, // Fixed header
+		0x00, 0x02, // Packet ID
+		0x00, 0x03, b'a', b'/', b'+', // Topic Filter
+		0x00, 0x03, b'b', b'/', b'#' // Topic Filter
+	);
+
+	match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+		IResult::Done(i, o) => {
+			assert_eq!(i, &[]);
+			assert_eq!(o, Packet::Unsubscribe {
+				packet_id: 2,
+				properties: Vec::new(),
+				topic_filters: vec!(TopicFilter::new("a/+").unwrap(), TopicFilter::new("b/#").unwrap())
+			});
+		}
+		e => panic!("{:?}", e)
+	}
+}
+
+#[test]
+fn test_unsubscribe_packet_parser_invalid() {
+	let cases = vec!(
+		// Reserved fixed header bits are 0000
+		(vec!(0xA0, 0x05, 0x00, 0x02, 0x00, 0x01, b'a'), MqttParseError::InvalidFixedHeaderFlags),
+		// No topic filters
+		(vec!(0xA2, 0x02, 0x00, 0x02), MqttParseError::EmptyTopicFilterList),
+		(vec!(0xA2, 0x04, 0x00, 0x02, 0x00, 0x00), MqttParseError::InvalidTopic(TopicError::Empty))
+	);
+
+	for (test_input, expected) in cases {
+		match packet_parser(&test_input, ProtocolVersion::Mqtt311) {
+			IResult::Error(e) => assert_eq!(decode_error(e, &test_input, None).kind, expected),
+			e => panic!("{:?}", e)
+		}
+	}
+}
+
+#[test]
+fn test_consumer_split_packet() {
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	let packet = vec!(0x30, 0x06, 0x00, 0x01, b'a', b'h', b'e', b'y');
+
+	assert_eq!(consumer.feed_bytes(&packet[..1]), Ok(vec!()));
+	assert_eq!(consumer.feed_bytes(&packet[1..5]), Ok(vec!()));
+	assert_eq!(consumer.buffered_len(), 5);
+
+	assert_eq!(consumer.feed_bytes(&packet[5..]), Ok(vec!(
+		Packet::Publish(PublishPacket {
+			dup: false,
+			qos: QualityOfService::AtMostOnce,
+			retain: false,
+			topic_name: TopicName::new("a").unwrap(),
+			packet_id: None,
+			properties: Vec::new(),
+			payload: b"hey".to_vec()
+		})
+	)));
+	assert_eq!(consumer.buffered_len(), 0);
+}
+
+#[test]
+fn test_consumer_coalesced_packets() {
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	// Two whole packets followed by the first byte of a third
+	let bytes = vec!(0xC0, 0x00, 0x40, 0x02, 0x00, 0x07, 0xE0);
+
+	assert_eq!(consumer.feed_bytes(&bytes), Ok(vec!(
+		Packet::PingRequest,
+		Packet::PublishAck { packet_id: 7, reason_code: ReasonCode::Success, properties: Vec::new() }
+	)));
+	assert_eq!(consumer.buffered_len(), 1);
+
+	assert_eq!(consumer.feed_bytes(&[0x00]), Ok(vec!(Packet::Disconnect { reason_code: ReasonCode::Success, properties: Vec::new() })));
+	assert_eq!(consumer.buffered_len(), 0);
+}
+
+#[test]
+fn test_consumer_large_packet() {
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	let payload = vec!(0xAB; 100_000);
+	let mut bytes = vec!(0x30);
+	::encoder::encode_remaining_length(payload.len() + 3, &mut bytes).unwrap();
+	bytes.extend_from_slice(&[0x00, 0x01, b'a']);
+	bytes.extend_from_slice(&payload);
+
+	let mut packets = Vec::new();
+
+	for chunk in bytes.chunks(1024) {
+		packets.extend(consumer.feed_bytes(chunk).unwrap());
+	}
+
+	assert_eq!(packets, vec!(
+		Packet::Publish(PublishPacket {
+			dup: false,
+			qos: QualityOfService::AtMostOnce,
+			retain: false,
+			topic_name: TopicName::new("a").unwrap(),
+			packet_id: None,
+			properties: Vec::new(),
+			payload: payload
+		})
+	));
+}
+
+#[test]
+fn test_consumer_invalid_packet() {
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	assert_eq!(consumer.feed_bytes(&[0x00, 0x00]), Err(DecodeError {
+		kind: MqttParseError::InvalidControlType,
+		control_type: None,
+		field: None,
+		offset: 0
+	}));
+
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	let bytes = vec!(
+		0x10, 0x0E, // Fixed header
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x04, // Protocol Level
+		0x02, // Connect Flags
+		0x00, 0x3C, // Keep alive
+		0x00, 0x02, 0xC3, 0x28 // Client ID, which isn't valid UTF-8
+	);
+
+	let error = consumer.feed_bytes(&bytes).unwrap_err();
+
+	assert_eq!(error, DecodeError {
+		kind: MqttParseError::InvalidUTF8Sequence,
+		control_type: Some(ControlPacketType::Connect),
+		field: Some(PacketField::ClientId),
+		offset: 14
+	});
+	assert_eq!(format!("{}", error), "invalid UTF-8 sequence in the client identifier of a CONNECT packet at byte 14");
+}
+
+#[test]
+fn test_consumer_switches_version() {
+	let mut consumer = MqttConsumer::new(1024 * 1024);
+
+	// An MQTT 5 CONNECT followed by a PUBACK with a reason code, which only parses as MQTT 5
+	let bytes = vec!(
+		0x10, 0x0F, // Fixed header
+		0x00, 0x04, b'M', b'Q', b'T', b'T', // Protocol Name
+		0x05, // Protocol Level
+		0x02, // Connect Flags
+		0x00, 0x3C, // Keep alive
+		0x00, // Properties
+		0x00, 0x02, b'i', b'd', // Client ID
+		0x40, 0x03, 0x00, 0x01, 0x10 // PUBACK
+	);
+
+	let packets = consumer.feed_bytes(&bytes).unwrap();
+
+	assert_eq!(packets.len(), 2);
+	assert_eq!(packets[1], Packet::PublishAck {
+		packet_id: 1,
+		reason_code: ReasonCode::NoMatchingSubscribers,
+		properties: Vec::new()
+	});
+}
+
+#[test]
+fn test_consumer_packet_too_large() {
+	let mut consumer = MqttConsumer::new(64);
+
+	// A PUBLISH which declares 100 bytes of remaining length, but only sends the fixed header
+	assert_eq!(consumer.feed_bytes(&[0x30, 0x64]).unwrap_err().kind, MqttParseError::PacketTooLarge);
+
+	// The size limit includes the fixed header
+	let mut consumer = MqttConsumer::new(64);
+	assert_eq!(consumer.feed_bytes(&[0x30, 0x3F]).unwrap_err().kind, MqttParseError::PacketTooLarge);
+
+	let mut consumer = MqttConsumer::new(64);
+	assert_eq!(consumer.feed_bytes(&[0x30, 0x3E]), Ok(vec!()));
+}
@@ -0,0 +1,97 @@
+use std::convert::TryFrom;
+use super::{MqttParseError, ReasonCode};
+
+// The CONNACK return codes of MQTT 3.1 and 3.1.1. 6-255 are reserved.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectReturnCode {
+	Accepted,
+	UnacceptableProtocolVersion,
+	IdentifierRejected,
+	ServerUnavailable,
+	BadUsernameOrPassword,
+	NotAuthorized
+}
+
+impl TryFrom<u8> for ConnectReturnCode {
+	type Err = MqttParseError;
+
+	fn try_from(original: u8) -> Result<ConnectReturnCode, MqttParseError> {
+		match original {
+			0 => Ok(ConnectReturnCode::Accepted),
+			1 => Ok(ConnectReturnCode::UnacceptableProtocolVersion),
+			2 => Ok(ConnectReturnCode::IdentifierRejected),
+			3 => Ok(ConnectReturnCode::ServerUnavailable),
+			4 => Ok(ConnectReturnCode::BadUsernameOrPassword),
+			5 => Ok(ConnectReturnCode::NotAuthorized),
+			_ => Err(MqttParseError::InvalidConnectReturnCode)
+		}
+	}
+}
+
+impl From<ConnectReturnCode> for u8 {
+	fn from(return_code: ConnectReturnCode) -> u8 {
+		match return_code {
+			ConnectReturnCode::Accepted => 0,
+			ConnectReturnCode::UnacceptableProtocolVersion => 1,
+			ConnectReturnCode::IdentifierRejected => 2,
+			ConnectReturnCode::ServerUnavailable => 3,
+			ConnectReturnCode::BadUsernameOrPassword => 4,
+			ConnectReturnCode::NotAuthorized => 5
+		}
+	}
+}
+
+// MQTT 5 sends a reason code instead, so the broker works with reason codes and
+// only converts to a return code when writing a CONNACK to an older client
+impl From<ReasonCode> for ConnectReturnCode {
+	fn from(reason_code: ReasonCode) -> ConnectReturnCode {
+		match reason_code {
+			ReasonCode::Success => ConnectReturnCode::Accepted,
+			ReasonCode::UnsupportedProtocolVersion => ConnectReturnCode::UnacceptableProtocolVersion,
+			ReasonCode::ClientIdentifierNotValid => ConnectReturnCode::IdentifierRejected,
+			ReasonCode::BadUsernameOrPassword => ConnectReturnCode::BadUsernameOrPassword,
+			ReasonCode::NotAuthorized | ReasonCode::Banned => ConnectReturnCode::NotAuthorized,
+			// Anything without an equivalent is reported as the server being unavailable
+			_ => ConnectReturnCode::ServerUnavailable
+		}
+	}
+}
+
+impl From<ConnectReturnCode> for ReasonCode {
+	fn from(return_code: ConnectReturnCode) -> ReasonCode {
+		match return_code {
+			ConnectReturnCode::Accepted => ReasonCode::Success,
+			ConnectReturnCode::UnacceptableProtocolVersion => ReasonCode::UnsupportedProtocolVersion,
+			ConnectReturnCode::IdentifierRejected => ReasonCode::ClientIdentifierNotValid,
+			ConnectReturnCode::ServerUnavailable => ReasonCode::ServerUnavailable,
+			ConnectReturnCode::BadUsernameOrPassword => ReasonCode::BadUsernameOrPassword,
+			ConnectReturnCode::NotAuthorized => ReasonCode::NotAuthorized
+		}
+	}
+}
+
+#[test]
+fn test_from_u8() {
+	for n in 0..6 {
+		let return_code = ConnectReturnCode::try_from(n).unwrap();
+		assert_eq!(u8::from(return_code), n);
+	}
+
+	for n in 6..256 {
+		match ConnectReturnCode::try_from(n) {
+			Err(MqttParseError::InvalidConnectReturnCode) => assert!(true),
+			_ => assert!(false)
+		}
+	}
+}
+
+#[test]
+fn test_reason_code_conversion() {
+	for n in 0..6 {
+		let return_code = ConnectReturnCode::try_from(n).unwrap();
+		assert_eq!(ConnectReturnCode::from(ReasonCode::from(return_code)), return_code);
+	}
+
+	assert_eq!(ConnectReturnCode::from(ReasonCode::Banned), ConnectReturnCode::NotAuthorized);
+	assert_eq!(ConnectReturnCode::from(ReasonCode::ServerBusy), ConnectReturnCode::ServerUnavailable);
+}
@@ -1,7 +1,8 @@
 use std::convert::TryFrom;
+use std::fmt;
 use super::MqttParseError;
 
-// 0 and 15 are reserved
+// 0 is reserved, and 15 is reserved before MQTT 5
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ControlPacketType {
 	Connect,
@@ -17,7 +18,32 @@ pub enum ControlPacketType {
 	UnsubscribeAck,
 	PingRequest,
 	PingResponse,
-	Disconnect
+	Disconnect,
+	Auth
+}
+
+impl fmt::Display for ControlPacketType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match *self {
+			ControlPacketType::Connect => "CONNECT",
+			ControlPacketType::ConnectAck => "CONNACK",
+			ControlPacketType::Publish => "PUBLISH",
+			ControlPacketType::PublishAck => "PUBACK",
+			ControlPacketType::PublishReceived => "PUBREC",
+			ControlPacketType::PublishRelease => "PUBREL",
+			ControlPacketType::PublishComplete => "PUBCOMP",
+			ControlPacketType::Subscribe => "SUBSCRIBE",
+			ControlPacketType::SubscribeAck => "SUBACK",
+			ControlPacketType::Unsubscribe => "UNSUBSCRIBE",
+			ControlPacketType::UnsubscribeAck => "UNSUBACK",
+			ControlPacketType::PingRequest => "PINGREQ",
+			ControlPacketType::PingResponse => "PINGRESP",
+			ControlPacketType::Disconnect => "DISCONNECT",
+			ControlPacketType::Auth => "AUTH"
+		};
+
+		write!(f, "{}", name)
+	}
 }
 
 impl TryFrom<u8> for ControlPacketType {
@@ -39,6 +65,7 @@ impl TryFrom<u8> for ControlPacketType {
 			12 => Ok(ControlPacketType::PingRequest),
 			13 => Ok(ControlPacketType::PingResponse),
 			14 => Ok(ControlPacketType::Disconnect),
+			15 => Ok(ControlPacketType::Auth),
 			_ => Err(MqttParseError::InvalidControlType)
 		}
 	}
@@ -65,8 +92,9 @@ fn test_from_u8() {
 	assert_eq!(ControlPacketType::try_from(12).unwrap(), ControlPacketType::PingRequest);
 	assert_eq!(ControlPacketType::try_from(13).unwrap(), ControlPacketType::PingResponse);
 	assert_eq!(ControlPacketType::try_from(14).unwrap(), ControlPacketType::Disconnect);
+	assert_eq!(ControlPacketType::try_from(15).unwrap(), ControlPacketType::Auth);
 
-	for n in 15..256 {
+	for n in 16..256 {
 		match ControlPacketType::try_from(n) {
 			Err(MqttParseError::InvalidControlType) => assert!(true),
 			_ => assert!(false)
@@ -1,33 +1,10 @@
 use protocol::control_packet_type::ControlPacketType;
 
+// The flags field holds the low nibble of the first byte. Its meaning depends
+// on the control type, and it's decoded into typed fields on the Packet.
 #[derive(Debug, PartialEq)]
 pub struct FixedHeader {
 	pub control_type: ControlPacketType,
-	pub bit_0: bool,
-	pub bit_1: bool,
-	pub bit_2: bool,
-	pub bit_3: bool,
+	pub flags: u8,
 	pub remaining_length: u32
 }
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct FirstByteData {
-	pub control_type: ControlPacketType,
-	pub bit_0: bool,
-	pub bit_1: bool,
-	pub bit_2: bool,
-	pub bit_3: bool
-}
-
-impl FixedHeader {
-	pub fn from_first_byte_data(first_byte: FirstByteData, remaining_length: u32) -> FixedHeader {
-		FixedHeader {
-			control_type: first_byte.control_type,
-			bit_0: first_byte.bit_0,
-			bit_1: first_byte.bit_1,
-			bit_2: first_byte.bit_2,
-			bit_3: first_byte.bit_3,
-			remaining_length: remaining_length
-		}
-	}
-}
\ No newline at end of file
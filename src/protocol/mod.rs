@@ -2,15 +2,22 @@ pub use self::control_packet_type::*;
 pub use self::fixed_header::*;
 pub use self::variable_header::*;
 pub use self::payload::*;
+pub use self::packet::*;
+pub use self::quality_of_service::*;
+pub use self::parse_error::*;
+pub use self::connect_return_code::*;
+pub use self::protocol_version::*;
+pub use self::reason_code::*;
+pub use self::property::*;
 
 pub mod control_packet_type;
 pub mod fixed_header;
 pub mod variable_header;
 pub mod payload;
-
-#[derive(Debug, PartialEq)]
-pub enum MqttParseError {
-	InvalidControlType,
-	InvalidRemainingLength,
-	InvalidUTF8Sequence
-}
+pub mod packet;
+pub mod quality_of_service;
+pub mod parse_error;
+pub mod connect_return_code;
+pub mod protocol_version;
+pub mod reason_code;
+pub mod property;
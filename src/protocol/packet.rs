@@ -0,0 +1,68 @@
+use protocol::control_packet_type::ControlPacketType;
+use protocol::property::Property;
+use protocol::quality_of_service::QualityOfService;
+use protocol::reason_code::ReasonCode;
+use protocol::variable_header::ConnectVariableHeader;
+use protocol::payload::{ConnectPayload, SubscribeReturnCode, SubscribeTopic};
+use topic::{TopicFilter, TopicName};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectPacket {
+	pub variable_header: ConnectVariableHeader,
+	pub payload: ConnectPayload
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishPacket {
+	pub dup: bool,
+	pub qos: QualityOfService,
+	pub retain: bool,
+	pub topic_name: TopicName,
+	pub packet_id: Option<u16>,
+	pub properties: Vec<Property>,
+	pub payload: Vec<u8>
+}
+
+// Packets hold the MQTT 5 fields as well. For earlier versions the properties are
+// always empty, and reason codes are Success apart from the CONNACK return code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+	Connect(ConnectPacket),
+	ConnectAck { session_present: bool, reason_code: ReasonCode, properties: Vec<Property> },
+	Publish(PublishPacket),
+	PublishAck { packet_id: u16, reason_code: ReasonCode, properties: Vec<Property> },
+	PublishReceived { packet_id: u16, reason_code: ReasonCode, properties: Vec<Property> },
+	PublishRelease { packet_id: u16, reason_code: ReasonCode, properties: Vec<Property> },
+	PublishComplete { packet_id: u16, reason_code: ReasonCode, properties: Vec<Property> },
+	Subscribe { packet_id: u16, properties: Vec<Property>, topics: Vec<SubscribeTopic> },
+	SubscribeAck { packet_id: u16, properties: Vec<Property>, return_codes: Vec<SubscribeReturnCode> },
+	Unsubscribe { packet_id: u16, properties: Vec<Property>, topic_filters: Vec<TopicFilter> },
+	// The reason codes are always empty before MQTT 5
+	UnsubscribeAck { packet_id: u16, properties: Vec<Property>, reason_codes: Vec<ReasonCode> },
+	PingRequest,
+	PingResponse,
+	Disconnect { reason_code: ReasonCode, properties: Vec<Property> },
+	Auth { reason_code: ReasonCode, properties: Vec<Property> }
+}
+
+impl Packet {
+	pub fn control_type(&self) -> ControlPacketType {
+		match *self {
+			Packet::Connect(_) => ControlPacketType::Connect,
+			Packet::ConnectAck { .. } => ControlPacketType::ConnectAck,
+			Packet::Publish(_) => ControlPacketType::Publish,
+			Packet::PublishAck { .. } => ControlPacketType::PublishAck,
+			Packet::PublishReceived { .. } => ControlPacketType::PublishReceived,
+			Packet::PublishRelease { .. } => ControlPacketType::PublishRelease,
+			Packet::PublishComplete { .. } => ControlPacketType::PublishComplete,
+			Packet::Subscribe { .. } => ControlPacketType::Subscribe,
+			Packet::SubscribeAck { .. } => ControlPacketType::SubscribeAck,
+			Packet::Unsubscribe { .. } => ControlPacketType::Unsubscribe,
+			Packet::UnsubscribeAck { .. } => ControlPacketType::UnsubscribeAck,
+			Packet::PingRequest => ControlPacketType::PingRequest,
+			Packet::PingResponse => ControlPacketType::PingResponse,
+			Packet::Disconnect { .. } => ControlPacketType::Disconnect,
+			Packet::Auth { .. } => ControlPacketType::Auth
+		}
+	}
+}
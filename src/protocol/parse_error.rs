@@ -0,0 +1,180 @@
+use std::error::Error;
+use std::fmt;
+use protocol::control_packet_type::ControlPacketType;
+use topic::TopicError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketField {
+	ProtocolName,
+	ProtocolLevel,
+	ConnectFlags,
+	KeepAlive,
+	ClientId,
+	WillTopic,
+	WillMessage,
+	Username,
+	Password,
+	ConnectAckFlags,
+	ReturnCode,
+	TopicName,
+	PacketId,
+	TopicFilter,
+	RequestedQualityOfService,
+	SubscribeReturnCode,
+	Properties,
+	WillProperties,
+	ReasonCode,
+	SubscriptionOptions
+}
+
+impl fmt::Display for PacketField {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match *self {
+			PacketField::ProtocolName => "protocol name",
+			PacketField::ProtocolLevel => "protocol level",
+			PacketField::ConnectFlags => "connect flags",
+			PacketField::KeepAlive => "keep alive",
+			PacketField::ClientId => "client identifier",
+			PacketField::WillTopic => "will topic",
+			PacketField::WillMessage => "will message",
+			PacketField::Username => "user name",
+			PacketField::Password => "password",
+			PacketField::ConnectAckFlags => "connect acknowledge flags",
+			PacketField::ReturnCode => "return code",
+			PacketField::TopicName => "topic name",
+			PacketField::PacketId => "packet identifier",
+			PacketField::TopicFilter => "topic filter",
+			PacketField::RequestedQualityOfService => "requested QoS",
+			PacketField::SubscribeReturnCode => "subscribe return code",
+			PacketField::Properties => "properties",
+			PacketField::WillProperties => "will properties",
+			PacketField::ReasonCode => "reason code",
+			PacketField::SubscriptionOptions => "subscription options"
+		};
+
+		write!(f, "{}", name)
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MqttParseError {
+	InvalidControlType,
+	InvalidFixedHeaderFlags,
+	InvalidRemainingLength,
+	InvalidUTF8Sequence,
+	InvalidQualityOfService,
+	ConnectReservedFlagSet,
+	InvalidWillQualityOfService,
+	WillQualityOfServiceWithoutWillFlag,
+	WillRetainWithoutWillFlag,
+	PasswordWithoutUsername,
+	SubscriptionOptionsReservedBitsSet,
+	EmptyTopicFilterList,
+	InvalidSubscribeReturnCode,
+	InvalidConnectAckFlags,
+	InvalidConnectReturnCode,
+	InvalidReasonCode,
+	InvalidPropertyIdentifier,
+	InvalidVariableByteInteger,
+	InvalidRetainHandling,
+	InvalidPacketId,
+	InvalidTopic(TopicError),
+	MalformedPacket,
+	PacketTooLarge,
+	// Wraps the error from a field parser to record which field it was.
+	// It never makes it out of the decoder, see DecodeError.
+	InvalidField(PacketField)
+}
+
+impl fmt::Display for MqttParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			MqttParseError::InvalidField(field) => write!(f, "invalid {}", field),
+			_ => write!(f, "{}", self.description())
+		}
+	}
+}
+
+impl Error for MqttParseError {
+	fn description(&self) -> &str {
+		match *self {
+			MqttParseError::InvalidControlType => "invalid control packet type",
+			MqttParseError::InvalidFixedHeaderFlags => "invalid fixed header flags for the packet type",
+			MqttParseError::InvalidRemainingLength => "remaining length does not match the packet contents",
+			MqttParseError::InvalidUTF8Sequence => "invalid UTF-8 sequence",
+			MqttParseError::InvalidQualityOfService => "invalid QoS level",
+			MqttParseError::ConnectReservedFlagSet => "reserved connect flag is set",
+			MqttParseError::InvalidWillQualityOfService => "invalid will QoS level",
+			MqttParseError::WillQualityOfServiceWithoutWillFlag => "will QoS is set without the will flag",
+			MqttParseError::WillRetainWithoutWillFlag => "will retain is set without the will flag",
+			MqttParseError::PasswordWithoutUsername => "password flag is set without the user name flag",
+			MqttParseError::SubscriptionOptionsReservedBitsSet => "reserved bits of the requested QoS are set",
+			MqttParseError::EmptyTopicFilterList => "no topic filters were given",
+			MqttParseError::InvalidSubscribeReturnCode => "invalid subscribe return code",
+			MqttParseError::InvalidConnectAckFlags => "reserved connect acknowledge flags are set",
+			MqttParseError::InvalidConnectReturnCode => "invalid connect return code",
+			MqttParseError::InvalidReasonCode => "invalid reason code",
+			MqttParseError::InvalidPropertyIdentifier => "invalid property identifier",
+			MqttParseError::InvalidVariableByteInteger => "invalid variable byte integer",
+			MqttParseError::InvalidRetainHandling => "invalid retain handling option",
+			MqttParseError::InvalidPacketId => "packet identifier is 0",
+			MqttParseError::InvalidTopic(ref e) => e.description(),
+			MqttParseError::MalformedPacket => "malformed packet",
+			MqttParseError::PacketTooLarge => "packet is larger than the maximum packet size",
+			MqttParseError::InvalidField(_) => "invalid field"
+		}
+	}
+}
+
+// A parse error along with where in the packet it happened
+#[derive(Debug, PartialEq)]
+pub struct DecodeError {
+	pub kind: MqttParseError,
+	// None if the fixed header couldn't be decoded
+	pub control_type: Option<ControlPacketType>,
+	pub field: Option<PacketField>,
+	// Counted from the first byte of the packet
+	pub offset: usize
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "{}", self.kind));
+
+		if let Some(field) = self.field {
+			try!(write!(f, " in the {}", field));
+		}
+
+		match self.control_type {
+			Some(ref control_type) => write!(f, " of a {} packet at byte {}", control_type, self.offset),
+			None => write!(f, " in the fixed header at byte {}", self.offset)
+		}
+	}
+}
+
+impl Error for DecodeError {
+	fn description(&self) -> &str {
+		self.kind.description()
+	}
+}
+
+#[test]
+fn test_decode_error_display() {
+	let error = DecodeError {
+		kind: MqttParseError::InvalidUTF8Sequence,
+		control_type: Some(ControlPacketType::Connect),
+		field: Some(PacketField::ClientId),
+		offset: 14
+	};
+
+	assert_eq!(format!("{}", error), "invalid UTF-8 sequence in the client identifier of a CONNECT packet at byte 14");
+
+	let error = DecodeError {
+		kind: MqttParseError::InvalidControlType,
+		control_type: None,
+		field: None,
+		offset: 0
+	};
+
+	assert_eq!(format!("{}", error), "invalid control packet type in the fixed header at byte 0");
+}
@@ -1,32 +1,54 @@
-use protocol::control_packet_type::ControlPacketType;
+use protocol::property::Property;
+use protocol::quality_of_service::QualityOfService;
+use protocol::reason_code::ReasonCode;
+use topic::{TopicFilter, TopicName};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ConnectPayload {
 	pub client_id: String,
-	pub will_topic: Option<String>,
+	// Always empty before MQTT 5
+	pub will_properties: Vec<Property>,
+	pub will_topic: Option<TopicName>,
 	pub will_message: Option<Vec<u8>>,
 	pub username: Option<String>,
 	pub password: Option<Vec<u8>>
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Payload {
-	Connect(ConnectPayload),
-	ConnectAck,
-	Publish,
-	PublishAck,
-	PublishReceived,
-	PublishRelease,
-	PublishComplete,
-	Subscribe,
-	SubscribeAck,
-	Unsubscribe,
-	UnsubscribeAck,
-	PingRequest,
-	PingResponse,
-	Disconnect
+// Whether retained messages are sent when a subscription is made. Added in MQTT 5,
+// earlier versions always send them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetainHandling {
+	SendAtSubscribe,
+	SendAtNewSubscribe,
+	DoNotSend
 }
 
-impl Payload {
-	
+// no_local, retain_as_published and retain_handling are subscription options
+// added in MQTT 5, and keep their defaults for earlier versions
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscribeTopic {
+	pub topic_filter: TopicFilter,
+	pub qos: QualityOfService,
+	pub no_local: bool,
+	pub retain_as_published: bool,
+	pub retain_handling: RetainHandling
+}
+
+impl SubscribeTopic {
+	pub fn new(topic_filter: TopicFilter, qos: QualityOfService) -> SubscribeTopic {
+		SubscribeTopic {
+			topic_filter: topic_filter,
+			qos: qos,
+			no_local: false,
+			retain_as_published: false,
+			retain_handling: RetainHandling::SendAtSubscribe
+		}
+	}
+}
+
+// Before MQTT 5 every failure is sent as 0x80
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubscribeReturnCode {
+	Success(QualityOfService),
+	Failure(ReasonCode)
 }
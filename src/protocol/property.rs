@@ -0,0 +1,191 @@
+use std::convert::TryFrom;
+use super::MqttParseError;
+
+// MQTT 5 properties, which follow the variable header of most packets. Which
+// properties are allowed depends on the packet type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+	PayloadFormatIndicator(u8),
+	MessageExpiryInterval(u32),
+	ContentType(String),
+	ResponseTopic(String),
+	CorrelationData(Vec<u8>),
+	SubscriptionIdentifier(u32),
+	SessionExpiryInterval(u32),
+	AssignedClientIdentifier(String),
+	ServerKeepAlive(u16),
+	AuthenticationMethod(String),
+	AuthenticationData(Vec<u8>),
+	RequestProblemInformation(u8),
+	WillDelayInterval(u32),
+	RequestResponseInformation(u8),
+	ResponseInformation(String),
+	ServerReference(String),
+	ReasonString(String),
+	ReceiveMaximum(u16),
+	TopicAliasMaximum(u16),
+	TopicAlias(u16),
+	MaximumQualityOfService(u8),
+	RetainAvailable(u8),
+	UserProperty(String, String),
+	MaximumPacketSize(u32),
+	WildcardSubscriptionAvailable(u8),
+	SubscriptionIdentifierAvailable(u8),
+	SharedSubscriptionAvailable(u8)
+}
+
+// The identifier of each property, which also decides how its value is encoded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PropertyIdentifier {
+	PayloadFormatIndicator,
+	MessageExpiryInterval,
+	ContentType,
+	ResponseTopic,
+	CorrelationData,
+	SubscriptionIdentifier,
+	SessionExpiryInterval,
+	AssignedClientIdentifier,
+	ServerKeepAlive,
+	AuthenticationMethod,
+	AuthenticationData,
+	RequestProblemInformation,
+	WillDelayInterval,
+	RequestResponseInformation,
+	ResponseInformation,
+	ServerReference,
+	ReasonString,
+	ReceiveMaximum,
+	TopicAliasMaximum,
+	TopicAlias,
+	MaximumQualityOfService,
+	RetainAvailable,
+	UserProperty,
+	MaximumPacketSize,
+	WildcardSubscriptionAvailable,
+	SubscriptionIdentifierAvailable,
+	SharedSubscriptionAvailable
+}
+
+impl Property {
+	pub fn identifier(&self) -> PropertyIdentifier {
+		match *self {
+			Property::PayloadFormatIndicator(_) => PropertyIdentifier::PayloadFormatIndicator,
+			Property::MessageExpiryInterval(_) => PropertyIdentifier::MessageExpiryInterval,
+			Property::ContentType(_) => PropertyIdentifier::ContentType,
+			Property::ResponseTopic(_) => PropertyIdentifier::ResponseTopic,
+			Property::CorrelationData(_) => PropertyIdentifier::CorrelationData,
+			Property::SubscriptionIdentifier(_) => PropertyIdentifier::SubscriptionIdentifier,
+			Property::SessionExpiryInterval(_) => PropertyIdentifier::SessionExpiryInterval,
+			Property::AssignedClientIdentifier(_) => PropertyIdentifier::AssignedClientIdentifier,
+			Property::ServerKeepAlive(_) => PropertyIdentifier::ServerKeepAlive,
+			Property::AuthenticationMethod(_) => PropertyIdentifier::AuthenticationMethod,
+			Property::AuthenticationData(_) => PropertyIdentifier::AuthenticationData,
+			Property::RequestProblemInformation(_) => PropertyIdentifier::RequestProblemInformation,
+			Property::WillDelayInterval(_) => PropertyIdentifier::WillDelayInterval,
+			Property::RequestResponseInformation(_) => PropertyIdentifier::RequestResponseInformation,
+			Property::ResponseInformation(_) => PropertyIdentifier::ResponseInformation,
+			Property::ServerReference(_) => PropertyIdentifier::ServerReference,
+			Property::ReasonString(_) => PropertyIdentifier::ReasonString,
+			Property::ReceiveMaximum(_) => PropertyIdentifier::ReceiveMaximum,
+			Property::TopicAliasMaximum(_) => PropertyIdentifier::TopicAliasMaximum,
+			Property::TopicAlias(_) => PropertyIdentifier::TopicAlias,
+			Property::MaximumQualityOfService(_) => PropertyIdentifier::MaximumQualityOfService,
+			Property::RetainAvailable(_) => PropertyIdentifier::RetainAvailable,
+			Property::UserProperty(_, _) => PropertyIdentifier::UserProperty,
+			Property::MaximumPacketSize(_) => PropertyIdentifier::MaximumPacketSize,
+			Property::WildcardSubscriptionAvailable(_) => PropertyIdentifier::WildcardSubscriptionAvailable,
+			Property::SubscriptionIdentifierAvailable(_) => PropertyIdentifier::SubscriptionIdentifierAvailable,
+			Property::SharedSubscriptionAvailable(_) => PropertyIdentifier::SharedSubscriptionAvailable
+		}
+	}
+}
+
+impl TryFrom<u8> for PropertyIdentifier {
+	type Err = MqttParseError;
+
+	fn try_from(original: u8) -> Result<PropertyIdentifier, MqttParseError> {
+		match original {
+			0x01 => Ok(PropertyIdentifier::PayloadFormatIndicator),
+			0x02 => Ok(PropertyIdentifier::MessageExpiryInterval),
+			0x03 => Ok(PropertyIdentifier::ContentType),
+			0x08 => Ok(PropertyIdentifier::ResponseTopic),
+			0x09 => Ok(PropertyIdentifier::CorrelationData),
+			0x0B => Ok(PropertyIdentifier::SubscriptionIdentifier),
+			0x11 => Ok(PropertyIdentifier::SessionExpiryInterval),
+			0x12 => Ok(PropertyIdentifier::AssignedClientIdentifier),
+			0x13 => Ok(PropertyIdentifier::ServerKeepAlive),
+			0x15 => Ok(PropertyIdentifier::AuthenticationMethod),
+			0x16 => Ok(PropertyIdentifier::AuthenticationData),
+			0x17 => Ok(PropertyIdentifier::RequestProblemInformation),
+			0x18 => Ok(PropertyIdentifier::WillDelayInterval),
+			0x19 => Ok(PropertyIdentifier::RequestResponseInformation),
+			0x1A => Ok(PropertyIdentifier::ResponseInformation),
+			0x1C => Ok(PropertyIdentifier::ServerReference),
+			0x1F => Ok(PropertyIdentifier::ReasonString),
+			0x21 => Ok(PropertyIdentifier::ReceiveMaximum),
+			0x22 => Ok(PropertyIdentifier::TopicAliasMaximum),
+			0x23 => Ok(PropertyIdentifier::TopicAlias),
+			0x24 => Ok(PropertyIdentifier::MaximumQualityOfService),
+			0x25 => Ok(PropertyIdentifier::RetainAvailable),
+			0x26 => Ok(PropertyIdentifier::UserProperty),
+			0x27 => Ok(PropertyIdentifier::MaximumPacketSize),
+			0x28 => Ok(PropertyIdentifier::WildcardSubscriptionAvailable),
+			0x29 => Ok(PropertyIdentifier::SubscriptionIdentifierAvailable),
+			0x2A => Ok(PropertyIdentifier::SharedSubscriptionAvailable),
+			_ => Err(MqttParseError::InvalidPropertyIdentifier)
+		}
+	}
+}
+
+impl From<PropertyIdentifier> for u8 {
+	fn from(identifier: PropertyIdentifier) -> u8 {
+		match identifier {
+			PropertyIdentifier::PayloadFormatIndicator => 0x01,
+			PropertyIdentifier::MessageExpiryInterval => 0x02,
+			PropertyIdentifier::ContentType => 0x03,
+			PropertyIdentifier::ResponseTopic => 0x08,
+			PropertyIdentifier::CorrelationData => 0x09,
+			PropertyIdentifier::SubscriptionIdentifier => 0x0B,
+			PropertyIdentifier::SessionExpiryInterval => 0x11,
+			PropertyIdentifier::AssignedClientIdentifier => 0x12,
+			PropertyIdentifier::ServerKeepAlive => 0x13,
+			PropertyIdentifier::AuthenticationMethod => 0x15,
+			PropertyIdentifier::AuthenticationData => 0x16,
+			PropertyIdentifier::RequestProblemInformation => 0x17,
+			PropertyIdentifier::WillDelayInterval => 0x18,
+			PropertyIdentifier::RequestResponseInformation => 0x19,
+			PropertyIdentifier::ResponseInformation => 0x1A,
+			PropertyIdentifier::ServerReference => 0x1C,
+			PropertyIdentifier::ReasonString => 0x1F,
+			PropertyIdentifier::ReceiveMaximum => 0x21,
+			PropertyIdentifier::TopicAliasMaximum => 0x22,
+			PropertyIdentifier::TopicAlias => 0x23,
+			PropertyIdentifier::MaximumQualityOfService => 0x24,
+			PropertyIdentifier::RetainAvailable => 0x25,
+			PropertyIdentifier::UserProperty => 0x26,
+			PropertyIdentifier::MaximumPacketSize => 0x27,
+			PropertyIdentifier::WildcardSubscriptionAvailable => 0x28,
+			PropertyIdentifier::SubscriptionIdentifierAvailable => 0x29,
+			PropertyIdentifier::SharedSubscriptionAvailable => 0x2A
+		}
+	}
+}
+
+#[test]
+fn test_from_u8() {
+	let mut valid = 0;
+
+	for n in 0..256 {
+		let n = n as u8;
+
+		match PropertyIdentifier::try_from(n) {
+			Ok(identifier) => {
+				assert_eq!(u8::from(identifier), n);
+				valid += 1;
+			}
+			Err(e) => assert_eq!(e, MqttParseError::InvalidPropertyIdentifier)
+		}
+	}
+
+	assert_eq!(valid, 27);
+}
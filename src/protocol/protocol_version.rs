@@ -0,0 +1,95 @@
+// The protocol versions the broker accepts, negotiated from the protocol name
+// and level of a CONNECT packet
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProtocolVersion {
+	// Protocol level 3, named "MQIsdp"
+	Mqtt31,
+	// Protocol level 4, named "MQTT"
+	Mqtt311,
+	// Protocol level 5, named "MQTT"
+	Mqtt5
+}
+
+impl ProtocolVersion {
+	// None means the client should be refused with UnacceptableProtocolVersion
+	pub fn from_connect(protocol_name: &str, protocol_level: u8) -> Option<ProtocolVersion> {
+		match (protocol_name, protocol_level) {
+			("MQIsdp", 3) => Some(ProtocolVersion::Mqtt31),
+			("MQTT", 4) => Some(ProtocolVersion::Mqtt311),
+			("MQTT", 5) => Some(ProtocolVersion::Mqtt5),
+			_ => None
+		}
+	}
+
+	pub fn protocol_name(&self) -> &'static str {
+		match *self {
+			ProtocolVersion::Mqtt31 => "MQIsdp",
+			ProtocolVersion::Mqtt311 | ProtocolVersion::Mqtt5 => "MQTT"
+		}
+	}
+
+	pub fn protocol_level(&self) -> u8 {
+		match *self {
+			ProtocolVersion::Mqtt31 => 3,
+			ProtocolVersion::Mqtt311 => 4,
+			ProtocolVersion::Mqtt5 => 5
+		}
+	}
+
+	// Properties, reason codes and the AUTH packet only exist in MQTT 5
+	pub fn is_mqtt5(&self) -> bool {
+		*self == ProtocolVersion::Mqtt5
+	}
+
+	// 3.1 reserves the whole CONNACK flags byte, the session present flag arrived in 3.1.1
+	pub fn has_session_present(&self) -> bool {
+		*self != ProtocolVersion::Mqtt31
+	}
+
+	// MQTT 3.1 requires client ids of 1 to 23 characters. 3.1.1 lets the server
+	// accept longer ids, and an empty one if the client asks for a clean session.
+	// MQTT 5 allows an empty id with any session, and the server assigns one.
+	pub fn is_valid_client_id(&self, client_id: &str, clean_session: bool) -> bool {
+		match *self {
+			ProtocolVersion::Mqtt31 => {
+				let length = client_id.chars().count();
+				length >= 1 && length <= 23
+			}
+			ProtocolVersion::Mqtt311 => !client_id.is_empty() || clean_session,
+			ProtocolVersion::Mqtt5 => true
+		}
+	}
+}
+
+#[test]
+fn test_from_connect() {
+	assert_eq!(ProtocolVersion::from_connect("MQIsdp", 3), Some(ProtocolVersion::Mqtt31));
+	assert_eq!(ProtocolVersion::from_connect("MQTT", 4), Some(ProtocolVersion::Mqtt311));
+
+	assert_eq!(ProtocolVersion::from_connect("MQTT", 3), None);
+	assert_eq!(ProtocolVersion::from_connect("MQIsdp", 4), None);
+	assert_eq!(ProtocolVersion::from_connect("MQTT", 5), Some(ProtocolVersion::Mqtt5));
+	assert_eq!(ProtocolVersion::from_connect("MQIsdp", 5), None);
+	assert_eq!(ProtocolVersion::from_connect("MQTT", 6), None);
+	assert_eq!(ProtocolVersion::from_connect("mqtt", 4), None);
+
+	for version in vec!(ProtocolVersion::Mqtt31, ProtocolVersion::Mqtt311, ProtocolVersion::Mqtt5) {
+		assert_eq!(ProtocolVersion::from_connect(version.protocol_name(), version.protocol_level()), Some(version));
+	}
+}
+
+#[test]
+fn test_is_valid_client_id() {
+	let v31 = ProtocolVersion::Mqtt31;
+	assert!(v31.is_valid_client_id("a", true));
+	assert!(v31.is_valid_client_id("abcdefghijklmnopqrstuvw", false));
+	assert!(!v31.is_valid_client_id("abcdefghijklmnopqrstuvwx", false));
+	assert!(!v31.is_valid_client_id("", true));
+
+	let v311 = ProtocolVersion::Mqtt311;
+	assert!(v311.is_valid_client_id("abcdefghijklmnopqrstuvwx", false));
+	assert!(v311.is_valid_client_id("", true));
+	assert!(!v311.is_valid_client_id("", false));
+
+	assert!(ProtocolVersion::Mqtt5.is_valid_client_id("", false));
+}
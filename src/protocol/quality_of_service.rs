@@ -0,0 +1,47 @@
+use std::convert::TryFrom;
+use super::MqttParseError;
+
+// 3 is reserved
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum QualityOfService {
+	AtMostOnce,
+	AtLeastOnce,
+	ExactlyOnce
+}
+
+impl TryFrom<u8> for QualityOfService {
+	type Err = MqttParseError;
+
+	fn try_from(original: u8) -> Result<QualityOfService, MqttParseError> {
+		match original {
+			0 => Ok(QualityOfService::AtMostOnce),
+			1 => Ok(QualityOfService::AtLeastOnce),
+			2 => Ok(QualityOfService::ExactlyOnce),
+			_ => Err(MqttParseError::InvalidQualityOfService)
+		}
+	}
+}
+
+impl From<QualityOfService> for u8 {
+	fn from(qos: QualityOfService) -> u8 {
+		match qos {
+			QualityOfService::AtMostOnce => 0,
+			QualityOfService::AtLeastOnce => 1,
+			QualityOfService::ExactlyOnce => 2
+		}
+	}
+}
+
+#[test]
+fn test_from_u8() {
+	assert_eq!(QualityOfService::try_from(0).unwrap(), QualityOfService::AtMostOnce);
+	assert_eq!(QualityOfService::try_from(1).unwrap(), QualityOfService::AtLeastOnce);
+	assert_eq!(QualityOfService::try_from(2).unwrap(), QualityOfService::ExactlyOnce);
+
+	for n in 3..256 {
+		match QualityOfService::try_from(n) {
+			Err(MqttParseError::InvalidQualityOfService) => assert!(true),
+			_ => assert!(false)
+		}
+	}
+}
@@ -0,0 +1,181 @@
+use std::convert::TryFrom;
+use super::MqttParseError;
+
+// MQTT 5 reason codes. 0x00 is Success, Normal disconnection and Granted QoS 0
+// depending on the packet, and is just called Success here.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReasonCode {
+	Success,
+	GrantedQualityOfService1,
+	GrantedQualityOfService2,
+	DisconnectWithWillMessage,
+	NoMatchingSubscribers,
+	NoSubscriptionExisted,
+	ContinueAuthentication,
+	ReAuthenticate,
+	UnspecifiedError,
+	MalformedPacket,
+	ProtocolError,
+	ImplementationSpecificError,
+	UnsupportedProtocolVersion,
+	ClientIdentifierNotValid,
+	BadUsernameOrPassword,
+	NotAuthorized,
+	ServerUnavailable,
+	ServerBusy,
+	Banned,
+	ServerShuttingDown,
+	BadAuthenticationMethod,
+	KeepAliveTimeout,
+	SessionTakenOver,
+	TopicFilterInvalid,
+	TopicNameInvalid,
+	PacketIdentifierInUse,
+	PacketIdentifierNotFound,
+	ReceiveMaximumExceeded,
+	TopicAliasInvalid,
+	PacketTooLarge,
+	MessageRateTooHigh,
+	QuotaExceeded,
+	AdministrativeAction,
+	PayloadFormatInvalid,
+	RetainNotSupported,
+	QualityOfServiceNotSupported,
+	UseAnotherServer,
+	ServerMoved,
+	SharedSubscriptionsNotSupported,
+	ConnectionRateExceeded,
+	MaximumConnectTime,
+	SubscriptionIdentifiersNotSupported,
+	WildcardSubscriptionsNotSupported
+}
+
+impl ReasonCode {
+	// Codes of 0x80 and above report a failure
+	pub fn is_error(&self) -> bool {
+		u8::from(*self) >= 0x80
+	}
+}
+
+impl TryFrom<u8> for ReasonCode {
+	type Err = MqttParseError;
+
+	fn try_from(original: u8) -> Result<ReasonCode, MqttParseError> {
+		match original {
+			0x00 => Ok(ReasonCode::Success),
+			0x01 => Ok(ReasonCode::GrantedQualityOfService1),
+			0x02 => Ok(ReasonCode::GrantedQualityOfService2),
+			0x04 => Ok(ReasonCode::DisconnectWithWillMessage),
+			0x10 => Ok(ReasonCode::NoMatchingSubscribers),
+			0x11 => Ok(ReasonCode::NoSubscriptionExisted),
+			0x18 => Ok(ReasonCode::ContinueAuthentication),
+			0x19 => Ok(ReasonCode::ReAuthenticate),
+			0x80 => Ok(ReasonCode::UnspecifiedError),
+			0x81 => Ok(ReasonCode::MalformedPacket),
+			0x82 => Ok(ReasonCode::ProtocolError),
+			0x83 => Ok(ReasonCode::ImplementationSpecificError),
+			0x84 => Ok(ReasonCode::UnsupportedProtocolVersion),
+			0x85 => Ok(ReasonCode::ClientIdentifierNotValid),
+			0x86 => Ok(ReasonCode::BadUsernameOrPassword),
+			0x87 => Ok(ReasonCode::NotAuthorized),
+			0x88 => Ok(ReasonCode::ServerUnavailable),
+			0x89 => Ok(ReasonCode::ServerBusy),
+			0x8A => Ok(ReasonCode::Banned),
+			0x8B => Ok(ReasonCode::ServerShuttingDown),
+			0x8C => Ok(ReasonCode::BadAuthenticationMethod),
+			0x8D => Ok(ReasonCode::KeepAliveTimeout),
+			0x8E => Ok(ReasonCode::SessionTakenOver),
+			0x8F => Ok(ReasonCode::TopicFilterInvalid),
+			0x90 => Ok(ReasonCode::TopicNameInvalid),
+			0x91 => Ok(ReasonCode::PacketIdentifierInUse),
+			0x92 => Ok(ReasonCode::PacketIdentifierNotFound),
+			0x93 => Ok(ReasonCode::ReceiveMaximumExceeded),
+			0x94 => Ok(ReasonCode::TopicAliasInvalid),
+			0x95 => Ok(ReasonCode::PacketTooLarge),
+			0x96 => Ok(ReasonCode::MessageRateTooHigh),
+			0x97 => Ok(ReasonCode::QuotaExceeded),
+			0x98 => Ok(ReasonCode::AdministrativeAction),
+			0x99 => Ok(ReasonCode::PayloadFormatInvalid),
+			0x9A => Ok(ReasonCode::RetainNotSupported),
+			0x9B => Ok(ReasonCode::QualityOfServiceNotSupported),
+			0x9C => Ok(ReasonCode::UseAnotherServer),
+			0x9D => Ok(ReasonCode::ServerMoved),
+			0x9E => Ok(ReasonCode::SharedSubscriptionsNotSupported),
+			0x9F => Ok(ReasonCode::ConnectionRateExceeded),
+			0xA0 => Ok(ReasonCode::MaximumConnectTime),
+			0xA1 => Ok(ReasonCode::SubscriptionIdentifiersNotSupported),
+			0xA2 => Ok(ReasonCode::WildcardSubscriptionsNotSupported),
+			_ => Err(MqttParseError::InvalidReasonCode)
+		}
+	}
+}
+
+impl From<ReasonCode> for u8 {
+	fn from(reason_code: ReasonCode) -> u8 {
+		match reason_code {
+			ReasonCode::Success => 0x00,
+			ReasonCode::GrantedQualityOfService1 => 0x01,
+			ReasonCode::GrantedQualityOfService2 => 0x02,
+			ReasonCode::DisconnectWithWillMessage => 0x04,
+			ReasonCode::NoMatchingSubscribers => 0x10,
+			ReasonCode::NoSubscriptionExisted => 0x11,
+			ReasonCode::ContinueAuthentication => 0x18,
+			ReasonCode::ReAuthenticate => 0x19,
+			ReasonCode::UnspecifiedError => 0x80,
+			ReasonCode::MalformedPacket => 0x81,
+			ReasonCode::ProtocolError => 0x82,
+			ReasonCode::ImplementationSpecificError => 0x83,
+			ReasonCode::UnsupportedProtocolVersion => 0x84,
+			ReasonCode::ClientIdentifierNotValid => 0x85,
+			ReasonCode::BadUsernameOrPassword => 0x86,
+			ReasonCode::NotAuthorized => 0x87,
+			ReasonCode::ServerUnavailable => 0x88,
+			ReasonCode::ServerBusy => 0x89,
+			ReasonCode::Banned => 0x8A,
+			ReasonCode::ServerShuttingDown => 0x8B,
+			ReasonCode::BadAuthenticationMethod => 0x8C,
+			ReasonCode::KeepAliveTimeout => 0x8D,
+			ReasonCode::SessionTakenOver => 0x8E,
+			ReasonCode::TopicFilterInvalid => 0x8F,
+			ReasonCode::TopicNameInvalid => 0x90,
+			ReasonCode::PacketIdentifierInUse => 0x91,
+			ReasonCode::PacketIdentifierNotFound => 0x92,
+			ReasonCode::ReceiveMaximumExceeded => 0x93,
+			ReasonCode::TopicAliasInvalid => 0x94,
+			ReasonCode::PacketTooLarge => 0x95,
+			ReasonCode::MessageRateTooHigh => 0x96,
+			ReasonCode::QuotaExceeded => 0x97,
+			ReasonCode::AdministrativeAction => 0x98,
+			ReasonCode::PayloadFormatInvalid => 0x99,
+			ReasonCode::RetainNotSupported => 0x9A,
+			ReasonCode::QualityOfServiceNotSupported => 0x9B,
+			ReasonCode::UseAnotherServer => 0x9C,
+			ReasonCode::ServerMoved => 0x9D,
+			ReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+			ReasonCode::ConnectionRateExceeded => 0x9F,
+			ReasonCode::MaximumConnectTime => 0xA0,
+			ReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+			ReasonCode::WildcardSubscriptionsNotSupported => 0xA2
+		}
+	}
+}
+
+#[test]
+fn test_from_u8() {
+	let mut valid = 0;
+
+	for n in 0..256 {
+		let n = n as u8;
+
+		match ReasonCode::try_from(n) {
+			Ok(reason_code) => {
+				assert_eq!(u8::from(reason_code), n);
+				assert_eq!(reason_code.is_error(), n >= 0x80);
+				valid += 1;
+			}
+			Err(e) => assert_eq!(e, MqttParseError::InvalidReasonCode)
+		}
+	}
+
+	assert_eq!(valid, 43);
+}
@@ -1,68 +1,30 @@
-use protocol::control_packet_type::ControlPacketType;
+use protocol::property::Property;
+use protocol::protocol_version::ProtocolVersion;
+use protocol::quality_of_service::QualityOfService;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectFlags {
+	pub clean_session: bool,
+	pub will_flag: bool,
+	pub will_qos: QualityOfService,
+	pub will_retain: bool,
+	pub password: bool,
+	pub username: bool
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ConnectVariableHeader {
 	pub protocol_name: String,
 	pub protocol_level: u8,
-	pub connect_flags: u8,
-	pub keep_alive: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub struct ConnectAckVariableHeader {
-	pub flags: u8,
-	pub return_code: u8
-}
-
-#[derive(Debug, PartialEq)]
-pub struct PublishVariableHeader {
-	pub topic_name: String,
-	pub packet_id: Option<u16>
-}
-
-#[derive(Debug, PartialEq)]
-pub struct PublishAckVariableHeader {
-	pub packet_id: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub struct PublishReceivedVariableHeader {
-	pub packet_id: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub struct PublishReleaseVariableHeader {
-	pub packet_id: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub struct PublishCompleteVariableHeader {
-	pub packet_id: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub struct SubscribeVariableHeader {
-	pub packet_id: u16
-}
-
-#[derive(Debug, PartialEq)]
-pub enum VariableHeader {
-	Connect(ConnectVariableHeader),
-	ConnectAck(ConnectAckVariableHeader),
-	Publish(PublishVariableHeader),
-	PublishAck(PublishAckVariableHeader),
-	PublishReceived(PublishReceivedVariableHeader),
-	PublishRelease(PublishReleaseVariableHeader),
-	PublishComplete(PublishCompleteVariableHeader),
-	Subscribe(SubscribeVariableHeader),
-	SubscribeAck,
-	Unsubscribe,
-	UnsubscribeAck,
-	PingRequest,
-	PingResponse,
-	Disconnect
+	pub connect_flags: ConnectFlags,
+	pub keep_alive: u16,
+	// Always empty before MQTT 5
+	pub properties: Vec<Property>
 }
 
-impl VariableHeader {
-	
+impl ConnectVariableHeader {
+	// None if the broker doesn't support the requested version
+	pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+		ProtocolVersion::from_connect(&self.protocol_name, self.protocol_level)
+	}
 }
@@ -0,0 +1,243 @@
+use super::protocol::PublishPacket;
+use super::topic::{TopicFilter, TopicName};
+
+use std::collections::HashMap;
+
+// One level of a retained message's topic name
+struct Node {
+	children: HashMap<String, Node>,
+	// The message retained for the topic ending at this level
+	message: Option<PublishPacket>
+}
+
+impl Node {
+	fn new() -> Node {
+		Node {
+			children: HashMap::new(),
+			message: None
+		}
+	}
+}
+
+// The last message published with RETAIN set to each topic, sent to new subscribers.
+// They're kept in a trie of topic levels, like the SubscriptionTree, so a new subscription
+// only visits the levels its filter can match rather than every retained message.
+pub struct RetainedMessages {
+	root: Node,
+	message_count: usize
+}
+
+impl RetainedMessages {
+	pub fn new() -> RetainedMessages {
+		RetainedMessages {
+			root: Node::new(),
+			message_count: 0
+		}
+	}
+
+	// A retained message replaces the last one for its topic, and one with an empty payload just removes it
+	pub fn update(&mut self, publish: &PublishPacket) {
+		if publish.payload.is_empty() {
+			self.remove(&publish.topic_name);
+			return;
+		}
+
+		let mut node = &mut self.root;
+
+		for level in publish.topic_name.levels() {
+			node = node.children.entry(level.into()).or_insert_with(Node::new);
+		}
+
+		if node.message.is_none() {
+			self.message_count += 1;
+		}
+
+		node.message = Some(publish.clone());
+	}
+
+	fn remove(&mut self, topic_name: &TopicName) {
+		let levels: Vec<&str> = topic_name.levels().collect();
+
+		// Find the deepest node on the path which has to stay once the message is gone,
+		// and drop everything below it, the same way as SubscriptionTree::remove
+		let mut keep_depth = 0;
+
+		let prune = {
+			let mut node = &mut self.root;
+
+			for (depth, level) in levels.iter().enumerate() {
+				if depth > 0 && (node.message.is_some() || node.children.len() > 1) {
+					keep_depth = depth;
+				}
+
+				node = match node.children.get_mut(*level) {
+					Some(child) => child,
+					None => return
+				};
+			}
+
+			if node.message.take().is_none() {
+				return;
+			}
+
+			node.children.is_empty()
+		};
+
+		self.message_count -= 1;
+
+		if prune {
+			let mut node = &mut self.root;
+
+			for level in &levels[..keep_depth] {
+				node = node.children.get_mut(*level).expect("Retained message path disappeared while pruning");
+			}
+
+			node.children.remove(levels[keep_depth]);
+		}
+	}
+
+	pub fn get(&self, topic_name: &TopicName) -> Option<&PublishPacket> {
+		let mut node = &self.root;
+
+		for level in topic_name.levels() {
+			node = match node.children.get(level) {
+				Some(child) => child,
+				None => return None
+			};
+		}
+
+		node.message.as_ref()
+	}
+
+	pub fn matches(&self, topic_filter: &TopicFilter) -> Vec<&PublishPacket> {
+		let levels: Vec<&str> = topic_filter.levels().collect();
+		let mut matched = Vec::new();
+		let mut pending = vec!((&self.root, 0));
+
+		while let Some((node, depth)) = pending.pop() {
+			if depth == levels.len() {
+				matched.extend(node.message.as_ref());
+				continue;
+			}
+
+			// Wildcards at the start of a filter don't match topics starting with $
+			let children = node.children.iter().filter(|&(level, _)| depth > 0 || !level.starts_with('$'));
+
+			match levels[depth] {
+				// # matches the level above it, and everything below
+				"#" => {
+					matched.extend(node.message.as_ref());
+
+					let mut descendants: Vec<&Node> = children.map(|(_, child)| child).collect();
+
+					while let Some(descendant) = descendants.pop() {
+						matched.extend(descendant.message.as_ref());
+						descendants.extend(descendant.children.values());
+					}
+				}
+				"+" => pending.extend(children.map(|(_, child)| (child, depth + 1))),
+				level => pending.extend(node.children.get(level).map(|child| (child, depth + 1)))
+			}
+		}
+
+		matched
+	}
+
+	pub fn len(&self) -> usize {
+		self.message_count
+	}
+}
+
+#[cfg(test)]
+use super::protocol::QualityOfService;
+
+#[cfg(test)]
+fn publish(topic_name: &str, payload: &[u8]) -> PublishPacket {
+	PublishPacket {
+		dup: false,
+		qos: QualityOfService::AtMostOnce,
+		retain: true,
+		topic_name: TopicName::new(topic_name).unwrap(),
+		packet_id: None,
+		properties: Vec::new(),
+		payload: payload.to_vec()
+	}
+}
+
+#[test]
+fn test_update() {
+	let mut retained = RetainedMessages::new();
+	let topic_name = TopicName::new("a/b").unwrap();
+
+	retained.update(&publish("a/b", b"1"));
+	retained.update(&publish("a/b", b"2"));
+	assert_eq!(retained.len(), 1);
+	assert_eq!(retained.get(&topic_name), Some(&publish("a/b", b"2")));
+
+	retained.update(&publish("a/b", b""));
+	assert_eq!(retained.len(), 0);
+	assert_eq!(retained.get(&topic_name), None);
+}
+
+#[test]
+fn test_matches() {
+	let mut retained = RetainedMessages::new();
+
+	retained.update(&publish("a/b", b"1"));
+	retained.update(&publish("a/c", b"2"));
+	retained.update(&publish("b/c", b"3"));
+	retained.update(&publish("$SYS/uptime", b"4"));
+
+	let mut payloads = retained.matches(&TopicFilter::new("a/+").unwrap()).iter().map(|publish| publish.payload.clone()).collect::<Vec<_>>();
+	payloads.sort();
+	assert_eq!(payloads, vec!(b"1".to_vec(), b"2".to_vec()));
+
+	assert_eq!(retained.matches(&TopicFilter::new("#").unwrap()).len(), 3);
+	assert_eq!(retained.matches(&TopicFilter::new("$SYS/#").unwrap()).len(), 1);
+	assert_eq!(retained.matches(&TopicFilter::new("c").unwrap()).len(), 0);
+}
+
+#[test]
+fn test_matches_levels() {
+	let mut retained = RetainedMessages::new();
+
+	for topic in vec!("a", "a/b", "a/b/c", "a//c", "/a", "b/b/c", "$SYS/a/b") {
+		retained.update(&publish(topic, topic.as_bytes()));
+	}
+
+	for filter in vec!("a", "a/#", "a/+", "a/+/c", "+/+/c", "+", "#", "/+", "+/#", "a/b/c/#", "$SYS/+/b", "$SYS/#", "x/#") {
+		let topic_filter = TopicFilter::new(filter).unwrap();
+
+		let mut expected = vec!("a", "a/b", "a/b/c", "a//c", "/a", "b/b/c", "$SYS/a/b").into_iter()
+			.filter(|topic| topic_filter.matches(&TopicName::new(*topic).unwrap()))
+			.map(|topic| topic.as_bytes().to_vec())
+			.collect::<Vec<_>>();
+		expected.sort();
+
+		let mut payloads = retained.matches(&topic_filter).iter().map(|publish| publish.payload.clone()).collect::<Vec<_>>();
+		payloads.sort();
+
+		assert_eq!(payloads, expected, "{}", filter);
+	}
+}
+
+#[test]
+fn test_remove_prunes() {
+	let mut retained = RetainedMessages::new();
+
+	retained.update(&publish("a/b/c", b"1"));
+	retained.update(&publish("a", b"2"));
+
+	// Levels are dropped when nothing below them is retained any more, but a level with its own message stays
+	retained.update(&publish("a/b/c", b""));
+	assert!(retained.root.children["a"].children.is_empty());
+	assert_eq!(retained.len(), 1);
+
+	// Removing a topic with nothing retained changes nothing
+	retained.update(&publish("a/x", b""));
+	assert_eq!(retained.len(), 1);
+
+	retained.update(&publish("a", b""));
+	assert!(retained.root.children.is_empty());
+	assert_eq!(retained.len(), 0);
+}
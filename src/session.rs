@@ -1,97 +1,847 @@
 use super::session_state::{State};
+use super::client_state::ClientState;
+use super::config::Config;
+use super::encoder::encode_packet;
 use super::parser::MqttConsumer;
+use super::protocol::{ConnectPacket, ControlPacketType, MqttParseError, Packet, Property, PropertyIdentifier, ProtocolVersion, QualityOfService, ReasonCode};
+use super::protocol::{PublishPacket, RetainHandling, SubscribeReturnCode, SubscribeTopic};
+use super::subscription_tree::Subscription;
+use super::topic::TopicFilter;
+use super::write_queue::WriteQueue;
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::io;
 use std::io::{ErrorKind, Read};
+use std::mem;
+use std::time::{Duration, Instant};
+use std::u16;
 
 use mio::tcp::*;
 use mio::{Poll, PollOpt, Ready, Token};
 
+// Client ids the server assigns start with this, and clients can't choose ids which do
+const ASSIGNED_CLIENT_ID_PREFIX: &'static str = "auto-";
+
+// Changes to state shared between sessions, which the MqttHandler makes after each event
+#[derive(Debug, PartialEq)]
+pub enum Action {
+	Subscribe(TopicFilter, Subscription),
+	Unsubscribe(TopicFilter),
+	// CONNECT has been accepted, and the session is waiting for Session::resume
+	Connect { client_id: String, clean_session: bool },
+	// A message to send on to every matching subscriber
+	Publish(PublishPacket),
+	// Send this session the retained messages matching a filter it has subscribed to, at up to the granted QoS
+	SendRetained(TopicFilter, QualityOfService)
+}
+
 // An MQTT Session
 pub struct Session {
 	pub socket: TcpStream,
 	pub token: Token,
+	// Unlike the token, never reused for another connection
+	pub connection_id: u64,
 	pub state: State,
-	pub mqtt_consumer: MqttConsumer
+	pub mqtt_consumer: MqttConsumer,
+	// Encoded packets waiting for the socket to become writable
+	write_queue: WriteQueue,
+	// Set once a CONNECT has been accepted
+	pub protocol_version: Option<ProtocolVersion>,
+	pub client_id: Option<String>,
+	// How long the client state is kept after the connection closes, if it's kept at all
+	pub session_expiry: Option<Duration>,
+	// The longest the server keeps client state for
+	max_session_expiry: Duration,
+	pub client_state: ClientState,
+	// How many QoS 1 and 2 messages the client takes before acknowledging any. The rest wait in the queue.
+	receive_maximum: usize,
+	// The most messages which can wait in the queue
+	max_queued_messages: usize,
+	// The largest packet the client takes, if it has a limit. Anything bigger isn't sent.
+	max_outgoing_packet_size: Option<usize>,
+	actions: Vec<Action>,
+	// Packets which arrived while waiting for Session::resume
+	pending: VecDeque<Packet>,
+	// Sent with the CONNACK once the session has been resumed
+	connect_ack_properties: Vec<Property>,
+	// When the last packet was received from the client
+	last_received: Instant,
+	// How long the client may be silent before it's disconnected, if there's a limit.
+	// Until CONNECT arrives this is how long the client has to send it.
+	keep_alive: Option<Duration>,
+	// Published if the connection closes without a DISCONNECT
+	will: Option<PublishPacket>
 }
 
 impl Session {
-	pub fn new(socket: TcpStream, token: Token) -> Session {
+	pub fn new(socket: TcpStream, token: Token, connection_id: u64, config: &Config) -> Session {
 		Session {
 			socket: socket,
 			token: token,
-			state: State::Reading,
-			mqtt_consumer: MqttConsumer::new()
+			connection_id: connection_id,
+			state: State::Connecting,
+			mqtt_consumer: MqttConsumer::new(config.max_packet_size),
+			write_queue: WriteQueue::new(config.max_write_queue_size),
+			protocol_version: None,
+			client_id: None,
+			session_expiry: None,
+			max_session_expiry: config.session_expiry,
+			client_state: ClientState::new(),
+			receive_maximum: u16::MAX as usize,
+			max_queued_messages: config.max_queued_messages,
+			max_outgoing_packet_size: None,
+			actions: Vec::new(),
+			pending: VecDeque::new(),
+			connect_ack_properties: Vec::new(),
+			last_received: Instant::now(),
+			keep_alive: Some(config.connect_timeout),
+			will: None
 		}
 	}
 
-	pub fn handle_event(&mut self, poll: &mut Poll, event_type: Ready) -> io::Result<()> {
+	// Reads whatever has arrived. Replies are queued, and written along with anything still waiting
+	// from before the socket became writable once the MqttHandler has performed the session's actions.
+	pub fn handle_event(&mut self, event_type: Ready) {
 		println!("Session is ready for these events: {:?}", event_type);
 
-		match self.state {
-			State::Reading => {
-				assert!(event_type.is_readable());
-				try!(self.read(poll));
+		if event_type.is_readable() && self.is_open() {
+			self.read();
+		}
+
+		if event_type.is_hup() {
+			self.state = State::Closed;
+		}
+	}
+
+	fn read(&mut self) {
+		let mut buf = vec![0; 4096];
+
+		// With edge triggered events we won't be woken again for data which is
+		// already waiting, so keep reading until the socket would block
+		loop {
+			match self.socket.read(&mut buf) {
+				Ok(0) => {
+					println!("Client closed the connection");
+					self.state = State::Closed;
+					return;
+				},
+				Ok(n) => {
+					println!("Read {} bytes from socket", n);
+
+					match self.mqtt_consumer.feed_bytes(&buf[0..n]) {
+						Ok(packets) => {
+							for packet in packets {
+								self.last_received = Instant::now();
+								self.handle_packet(packet);
+
+								if !self.is_open() {
+									return;
+								}
+							}
+						}
+						Err(e) => {
+							match e.kind {
+								MqttParseError::PacketTooLarge => {
+									println!("{:?} sent a packet larger than the maximum packet size, closing the connection - {}", self.token, e);
+								}
+								_ => {
+									println!("{:?} sent a malformed packet, closing the connection - {}", self.token, e);
+								}
+							}
+
+							self.state = State::Closed;
+							return;
+						}
+					}
+				},
+				Err(e) => {
+					match e.kind() {
+						ErrorKind::WouldBlock => {
+							break;
+						}
+						ErrorKind::Interrupted => {}
+						_ => {
+							println!("Error calling read in Session - {}", e);
+							self.state = State::Closed;
+							return;
+						}
+					}
+				}
+			}
+		}
+	}
+
+	fn handle_packet(&mut self, packet: Packet) {
+		// Only the type is logged, packets can hold passwords and message payloads
+		println!("Received {} from {:?}", packet.control_type(), self.token);
+
+		if self.state == State::Resuming {
+			self.pending.push_back(packet);
+			return;
+		}
+
+		let control_type = packet.control_type();
+		let is_connect = control_type == ControlPacketType::Connect;
+
+		if self.state == State::Connecting && !is_connect {
+			// There's no protocol version to reply with yet, so just drop the connection
+			println!("{:?} sent {} before CONNECT, closing the connection", self.token, control_type);
+			self.state = State::Closed;
+			return;
+		}
+
+		if self.state == State::Connected && is_connect {
+			println!("{:?} sent a second CONNECT, closing the connection", self.token);
+			return self.protocol_error();
+		}
+
+		match packet {
+			Packet::Connect(connect) => self.handle_connect(connect),
+			Packet::Publish(publish) => self.handle_publish(publish),
+			Packet::PublishAck { packet_id, .. } => self.handle_publish_ack(packet_id),
+			Packet::PublishReceived { packet_id, reason_code, .. } => self.handle_publish_received(packet_id, reason_code),
+			Packet::PublishRelease { packet_id, .. } => self.handle_publish_release(packet_id),
+			Packet::PublishComplete { packet_id, .. } => self.handle_publish_complete(packet_id),
+			Packet::Subscribe { packet_id, properties, topics } => self.handle_subscribe(packet_id, properties, topics),
+			Packet::Unsubscribe { packet_id, topic_filters, .. } => self.handle_unsubscribe(packet_id, topic_filters),
+			// Receiving it is enough to reset the keep alive
+			Packet::PingRequest => {
+				self.send_packet(&Packet::PingResponse);
 			}
-			State::Writing => {
-				assert!(event_type.is_writable());
-				self.write(poll);
+			Packet::Disconnect { reason_code, .. } => self.handle_disconnect(reason_code),
+			// Only the server sends these, and AUTH needs an authentication method, which isn't supported
+			Packet::ConnectAck { .. } |
+			Packet::SubscribeAck { .. } |
+			Packet::UnsubscribeAck { .. } |
+			Packet::PingResponse |
+			Packet::Auth { .. } => {
+				println!("{:?} sent {}, which clients can't send, closing the connection", self.token, control_type);
+				self.protocol_error()
 			}
-			State::Closed => {
-				println!("Session was ready for reading but is in the Closed state");
+		}
+	}
+
+	fn handle_connect(&mut self, mut connect: ConnectPacket) {
+		let header = connect.variable_header;
+
+		let version = match header.protocol_version() {
+			Some(version) => version,
+			None => {
+				println!("{:?} requested unsupported protocol {} level {}, closing the connection", self.token, header.protocol_name, header.protocol_level);
+				return self.refuse_connect(ReasonCode::UnsupportedProtocolVersion);
 			}
+		};
+
+		// The CONNACK is sent using the version the client asked for, even if it's refused
+		self.protocol_version = Some(version);
+
+		if !version.is_valid_client_id(&connect.payload.client_id, header.connect_flags.clean_session) {
+			println!("{:?} sent an unacceptable client id {:?}, closing the connection", self.token, connect.payload.client_id);
+			return self.refuse_connect(ReasonCode::ClientIdentifierNotValid);
 		}
 
-		if event_type.is_hup() {
-			self.state = State::Closed;
+		// Otherwise a client could guess an assigned id, and take over the session of the client it was assigned to
+		if connect.payload.client_id.starts_with(ASSIGNED_CLIENT_ID_PREFIX) {
+			println!("{:?} chose the client id {:?}, which is reserved for the server to assign, closing the connection", self.token, connect.payload.client_id);
+			return self.refuse_connect(ReasonCode::ClientIdentifierNotValid);
+		}
+
+		// Topics starting with $ are reserved for the server, so a will can't be published to one. MQTT 5
+		// clients are refused, earlier versions have no return code for it and just connect without the will.
+		if connect.payload.will_topic.as_ref().map_or(false, |topic_name| topic_name.is_system()) {
+			println!("{:?} asked for a will on {}, which is reserved for the server", self.token, connect.payload.will_topic.as_ref().unwrap());
+
+			if version.is_mqtt5() {
+				return self.refuse_connect(ReasonCode::TopicNameInvalid);
+			}
+
+			connect.payload.will_topic = None;
+		}
+
+		// A client which has been silent for one and a half times its keep alive is treated as gone. 0 means no limit.
+		self.keep_alive = match header.keep_alive {
+			0 => None,
+			seconds => Some(Duration::from_millis(seconds as u64 * 1500))
+		};
+
+		// Clients can leave the client id for the server to pick, but only MQTT 5 clients are told what it is.
+		// Connection ids are never reused, so neither are the client ids made from them.
+		let client_id = if connect.payload.client_id.is_empty() {
+			let client_id = format!("{}{}", ASSIGNED_CLIENT_ID_PREFIX, self.connection_id);
+
+			if version.is_mqtt5() {
+				self.connect_ack_properties.push(Property::AssignedClientIdentifier(client_id.clone()));
+			}
+
+			client_id
+		} else {
+			connect.payload.client_id
+		};
+
+		// The will is published straight away, the MQTT 5 will delay interval isn't supported
+		if let (Some(topic_name), Some(message)) = (connect.payload.will_topic, connect.payload.will_message) {
+			self.will = Some(PublishPacket {
+				dup: false,
+				qos: header.connect_flags.will_qos,
+				retain: header.connect_flags.will_retain,
+				topic_name: topic_name,
+				packet_id: None,
+				properties: connect.payload.will_properties.into_iter()
+					.filter(|property| property.identifier() != PropertyIdentifier::WillDelayInterval)
+					.collect(),
+				payload: message
+			});
+		}
+
+		// MQTT 5 separates starting afresh from keeping the state afterwards, which it does for as long as
+		// the session expiry interval asks, up to the server's limit. Earlier versions always get the limit.
+		self.session_expiry = if version.is_mqtt5() {
+			let interval = header.properties.iter().filter_map(|property| match *property {
+				Property::SessionExpiryInterval(interval) => Some(interval),
+				_ => None
+			}).next().unwrap_or(0);
+
+			let requested = Duration::from_secs(interval as u64);
+			let session_expiry = cmp::min(requested, self.max_session_expiry);
+
+			// The client is told when it gets less than it asked for, as it does when it asks for 0xFFFFFFFF, meaning never
+			if session_expiry < requested {
+				self.connect_ack_properties.push(Property::SessionExpiryInterval(session_expiry.as_secs() as u32));
+			}
+
+			if session_expiry == Duration::from_secs(0) {
+				None
+			} else {
+				Some(session_expiry)
+			}
+		} else if header.connect_flags.clean_session {
+			None
+		} else {
+			Some(self.max_session_expiry)
+		};
+
+		// MQTT 5 clients can limit what they're sent. Neither limit can be 0.
+		for property in &header.properties {
+			match *property {
+				Property::ReceiveMaximum(0) | Property::MaximumPacketSize(0) => {
+					println!("{:?} sent {:?} in CONNECT, closing the connection", self.token, property);
+					return self.refuse_connect(ReasonCode::ProtocolError);
+				}
+				Property::ReceiveMaximum(receive_maximum) => self.receive_maximum = receive_maximum as usize,
+				Property::MaximumPacketSize(size) => self.max_outgoing_packet_size = Some(size as usize),
+				_ => {}
+			}
+		}
+
+		// Subscription identifiers and shared subscriptions aren't supported, and MQTT 5 clients have to be told
+		if version.is_mqtt5() {
+			self.connect_ack_properties.push(Property::SubscriptionIdentifierAvailable(0));
+			self.connect_ack_properties.push(Property::SharedSubscriptionAvailable(0));
 		}
 
-		Ok(())
+		self.client_id = Some(client_id.clone());
+
+		self.state = State::Resuming;
+		self.actions.push(Action::Connect {
+			client_id: client_id,
+			clean_session: header.connect_flags.clean_session
+		});
 	}
 
-	fn read(&mut self, poll: &mut Poll) -> io::Result<()> {
-		let mut buf = vec![0; 1024];
+	// Called by the MqttHandler after Action::Connect, with the state saved from the client's
+	// last connection if there's one to carry on with
+	pub fn resume(&mut self, saved: Option<ClientState>) {
+		let session_present = saved.is_some();
 
-		match self.socket.read(&mut buf) {
-			Ok(0) => {
-				println!("Read 0 bytes from socket, shit!");
-			},
-			Ok(n) => {
-				println!("Read {} bytes from socket jdfalfjadfdsa!", n);
-				self.mqtt_consumer.feed_bytes(&buf[0..n]);
-			},
-			Err(e) => {
-				match e.kind() {
-					ErrorKind::WouldBlock => {
-						println!("Socket would block here (session.rs)");
-						// self.reregister(poll);
-						// try!(self.reregister(poll));
+		// If the connection has closed in the meantime the state is still taken, so it can be saved again
+		if let Some(saved) = saved {
+			self.client_state = saved;
+		}
+
+		if self.state != State::Resuming {
+			return;
+		}
+
+		let properties = mem::replace(&mut self.connect_ack_properties, Vec::new());
+
+		self.send_packet(&Packet::ConnectAck {
+			session_present: session_present,
+			reason_code: ReasonCode::Success,
+			properties: properties
+		});
+
+		self.state = State::Connected;
+
+		// Anything the client hadn't acknowledged before it reconnected is sent again,
+		// followed by whatever was published while it was away
+		for packet in self.client_state.inflight.resend() {
+			self.send_packet(&packet);
+		}
+
+		self.send_queued();
+
+		while let Some(packet) = self.pending.pop_front() {
+			if !self.is_open() {
+				break;
+			}
+
+			self.handle_packet(packet);
+		}
+	}
+
+	// Nothing is written until the MqttHandler has routed the message, so the PUBACK or PUBREC
+	// can't reach the client before the message has been passed on
+	fn handle_publish(&mut self, publish: PublishPacket) {
+		if publish.topic_name.is_system() {
+			return self.refuse_publish(publish);
+		}
+
+		match (publish.qos, publish.packet_id) {
+			(QualityOfService::AtLeastOnce, Some(packet_id)) => {
+				self.actions.push(Action::Publish(publish));
+
+				self.send_packet(&Packet::PublishAck {
+					packet_id: packet_id,
+					reason_code: ReasonCode::Success,
+					properties: Vec::new()
+				});
+			}
+			(QualityOfService::ExactlyOnce, Some(packet_id)) => {
+				// A client which didn't get our PUBREC sends the message again, but it must only be routed once
+				if self.client_state.awaiting_release.insert(packet_id) {
+					self.actions.push(Action::Publish(publish));
+				} else {
+					println!("{:?} resent QoS 2 packet id {}, which has already been routed", self.token, packet_id);
+				}
+
+				self.send_packet(&Packet::PublishReceived {
+					packet_id: packet_id,
+					reason_code: ReasonCode::Success,
+					properties: Vec::new()
+				});
+			}
+			_ => self.actions.push(Action::Publish(publish))
+		}
+	}
+
+	// Topics starting with $ are reserved for the server, so messages clients publish to them aren't routed.
+	// MQTT 5 clients are told why in the acknowledgement, earlier versions get the usual one and the message is dropped.
+	fn refuse_publish(&mut self, publish: PublishPacket) {
+		println!("{:?} published to {}, which is reserved for the server", self.token, publish.topic_name);
+
+		let reason_code = if self.protocol_version == Some(ProtocolVersion::Mqtt5) {
+			ReasonCode::TopicNameInvalid
+		} else {
+			ReasonCode::Success
+		};
+
+		match (publish.qos, publish.packet_id) {
+			(QualityOfService::AtLeastOnce, Some(packet_id)) => {
+				self.send_packet(&Packet::PublishAck {
+					packet_id: packet_id,
+					reason_code: reason_code,
+					properties: Vec::new()
+				});
+			}
+			(QualityOfService::ExactlyOnce, Some(packet_id)) => {
+				// Without a reason code the client goes on to release the message, so PUBCOMP has to know about it
+				if !reason_code.is_error() {
+					self.client_state.awaiting_release.insert(packet_id);
+				}
+
+				self.send_packet(&Packet::PublishReceived {
+					packet_id: packet_id,
+					reason_code: reason_code,
+					properties: Vec::new()
+				});
+			}
+			_ => {}
+		}
+	}
+
+	fn handle_publish_release(&mut self, packet_id: u16) {
+		// Before MQTT 5 the reason code isn't sent, so the client always sees a plain PUBCOMP
+		let reason_code = if self.client_state.awaiting_release.remove(&packet_id) {
+			ReasonCode::Success
+		} else {
+			println!("{:?} released packet id {} which wasn't received", self.token, packet_id);
+			ReasonCode::PacketIdentifierNotFound
+		};
+
+		self.send_packet(&Packet::PublishComplete {
+			packet_id: packet_id,
+			reason_code: reason_code,
+			properties: Vec::new()
+		});
+	}
+
+	fn handle_publish_ack(&mut self, packet_id: u16) {
+		if self.client_state.inflight.acknowledge(packet_id).is_none() {
+			println!("{:?} acknowledged packet id {} which isn't inflight", self.token, packet_id);
+		}
+
+		self.send_queued();
+	}
+
+	fn handle_publish_received(&mut self, packet_id: u16, reason_code: ReasonCode) {
+		// MQTT 5 clients can refuse a message, which ends the exchange
+		if reason_code.is_error() {
+			println!("{:?} refused packet id {} - {:?}", self.token, packet_id, reason_code);
+			self.client_state.inflight.discard(packet_id);
+			return self.send_queued();
+		}
+
+		let reason_code = if self.client_state.inflight.received(packet_id) {
+			ReasonCode::Success
+		} else {
+			println!("{:?} received packet id {} which isn't inflight", self.token, packet_id);
+			ReasonCode::PacketIdentifierNotFound
+		};
+
+		self.send_packet(&Packet::PublishRelease {
+			packet_id: packet_id,
+			reason_code: reason_code,
+			properties: Vec::new()
+		});
+	}
+
+	fn handle_publish_complete(&mut self, packet_id: u16) {
+		if !self.client_state.inflight.complete(packet_id) {
+			println!("{:?} completed packet id {} which wasn't released", self.token, packet_id);
+		}
+
+		self.send_queued();
+	}
+
+	fn handle_disconnect(&mut self, reason_code: ReasonCode) {
+		// MQTT 5 clients can ask for their will to be published anyway
+		if reason_code != ReasonCode::DisconnectWithWillMessage {
+			self.will = None;
+		}
+
+		println!("{:?} disconnected", self.token);
+		self.state = State::Closed;
+	}
+
+	fn handle_subscribe(&mut self, packet_id: u16, properties: Vec<Property>, topics: Vec<SubscribeTopic>) {
+		// The CONNACK said subscription identifiers aren't available, so a client sending one has broken the protocol
+		if properties.iter().any(|property| property.identifier() == PropertyIdentifier::SubscriptionIdentifier) {
+			println!("{:?} sent a subscription identifier, which isn't supported, closing the connection", self.token);
+
+			return self.disconnect(ReasonCode::SubscriptionIdentifiersNotSupported);
+		}
+
+		let mut return_codes = Vec::new();
+
+		// Every QoS level is supported, so each filter is granted the QoS it asked for
+		for topic in topics {
+			// Earlier versions have no shared subscriptions, but a filter for one would only match topics
+			// starting with $share, which clients can't publish to, so it's refused for them as well
+			if topic.topic_filter.is_shared() {
+				println!("{:?} asked for the shared subscription {}, which isn't supported", self.token, topic.topic_filter);
+				return_codes.push(SubscribeReturnCode::Failure(ReasonCode::SharedSubscriptionsNotSupported));
+				continue;
+			}
+
+			let is_new = self.client_state.subscriptions.insert(topic.topic_filter.clone(), topic.qos).is_none();
+
+			// Before MQTT 5 retained messages are sent for every subscription, even ones which already existed
+			let send_retained = match topic.retain_handling {
+				RetainHandling::SendAtSubscribe => true,
+				RetainHandling::SendAtNewSubscribe => is_new,
+				RetainHandling::DoNotSend => false
+			};
+
+			self.actions.push(Action::Subscribe(topic.topic_filter.clone(), Subscription {
+				qos: topic.qos,
+				no_local: topic.no_local,
+				retain_as_published: topic.retain_as_published
+			}));
+
+			if send_retained {
+				self.actions.push(Action::SendRetained(topic.topic_filter, topic.qos));
+			}
+
+			return_codes.push(SubscribeReturnCode::Success(topic.qos));
+		}
+
+		self.send_packet(&Packet::SubscribeAck {
+			packet_id: packet_id,
+			properties: Vec::new(),
+			return_codes: return_codes
+		});
+	}
+
+	fn handle_unsubscribe(&mut self, packet_id: u16, topic_filters: Vec<TopicFilter>) {
+		let mut reason_codes = Vec::new();
+
+		for topic_filter in topic_filters {
+			if self.client_state.subscriptions.remove(&topic_filter).is_some() {
+				self.actions.push(Action::Unsubscribe(topic_filter));
+				reason_codes.push(ReasonCode::Success);
+			} else {
+				reason_codes.push(ReasonCode::NoSubscriptionExisted);
+			}
+		}
+
+		self.send_packet(&Packet::UnsubscribeAck {
+			packet_id: packet_id,
+			properties: Vec::new(),
+			reason_codes: reason_codes
+		});
+	}
+
+	// Sends a message published by any session to this one, which has a subscription matching its topic.
+	// RETAIN is only set on retained messages sent because of a new subscription.
+	pub fn deliver(&mut self, publish: &PublishPacket, granted_qos: QualityOfService, retain: bool) {
+		if self.state != State::Connected {
+			return;
+		}
+
+		// The subscriber gets the lower of the QoS the message was published with and the QoS it was granted
+		let qos = cmp::min(publish.qos, granted_qos);
+
+		// Once the client has as many QoS 1 and 2 messages as it takes at once, the rest wait
+		// for acknowledgements, behind any already waiting so they're sent in order
+		if qos != QualityOfService::AtMostOnce && (self.client_state.inflight.len() >= self.receive_maximum || !self.client_state.queued.is_empty()) {
+			if !self.client_state.queue(publish, granted_qos, retain, self.max_queued_messages) {
+				println!("The queue for {:?} is full, dropping a message for {}", self.token, publish.topic_name);
+			}
+
+			return;
+		}
+
+		self.send_publish(publish, qos, retain);
+	}
+
+	// Sends the messages waiting in the queue, for as long as the client has room for them
+	fn send_queued(&mut self) {
+		while self.state == State::Connected && self.client_state.inflight.len() < self.receive_maximum {
+			match self.client_state.queued.pop_front() {
+				Some((publish, granted_qos)) => self.send_publish(&publish, cmp::min(publish.qos, granted_qos), publish.retain),
+				None => break
+			}
+		}
+	}
+
+	fn send_publish(&mut self, publish: &PublishPacket, qos: QualityOfService, retain: bool) {
+		let packet_id = match qos {
+			QualityOfService::AtMostOnce => None,
+			_ => {
+				match self.client_state.take_packet_id() {
+					Some(packet_id) => Some(packet_id),
+					None => {
+						println!("{:?} has no free packet ids, dropping a message for {}", self.token, publish.topic_name);
+						return;
 					}
-					_ => println!("Error calling write in Session - {}", e)
 				}
 			}
+		};
+
+		// Topic aliases only apply to the connection they were set up on
+		let properties = publish.properties.iter()
+			.filter(|property| property.identifier() != PropertyIdentifier::TopicAlias)
+			.cloned()
+			.collect();
+
+		let outgoing = PublishPacket {
+			dup: false,
+			qos: qos,
+			retain: retain,
+			topic_name: publish.topic_name.clone(),
+			packet_id: packet_id,
+			properties: properties,
+			payload: publish.payload.clone()
+		};
+
+		// Kept until the client acknowledges it, in case it has to be sent again. A message which wasn't
+		// sent because it couldn't be encoded, or was too big for the client, would only fail again.
+		if self.send_packet(&Packet::Publish(outgoing.clone())) && packet_id.is_some() {
+			self.client_state.inflight.insert(outgoing);
+		}
+	}
+
+	pub fn keep_alive_deadline(&self) -> Option<Instant> {
+		self.keep_alive.map(|keep_alive| self.last_received + keep_alive)
+	}
+
+	// Closes the connection if the client has been silent for too long, otherwise returns when to check again
+	pub fn check_keep_alive(&mut self, now: Instant) -> Option<Instant> {
+		if self.is_closed() {
+			return None;
 		}
 
-		try!(self.reregister(poll));
+		match self.keep_alive_deadline() {
+			Some(deadline) if deadline <= now => {
+				if self.state == State::Connecting {
+					println!("{:?} didn't send CONNECT in time, closing the connection", self.token);
+				} else {
+					println!("{:?} exceeded its keep alive, closing the connection", self.token);
+				}
+
+				self.state = State::Closed;
+				None
+			}
+			deadline => deadline
+		}
+	}
 
-		Ok(())
+	// The will to publish now the session has closed, if it didn't close cleanly
+	pub fn take_will(&mut self) -> Option<PublishPacket> {
+		self.will.take()
 	}
 
-	fn write(&mut self, _: &mut Poll) {
+	// Closes the connection because another one has presented the same client id,
+	// and hands over the client state for the new connection to carry on with
+	pub fn take_over(&mut self, publish_will: bool) -> ClientState {
+		println!("{:?} has been taken over by a new connection, closing the connection", self.token);
 
+		if !publish_will {
+			self.will = None;
+		}
+
+		if self.protocol_version == Some(ProtocolVersion::Mqtt5) && self.state == State::Connected {
+			self.send_packet(&Packet::Disconnect {
+				reason_code: ReasonCode::SessionTakenOver,
+				properties: Vec::new()
+			});
+
+			self.state = State::Closing;
+		} else {
+			self.state = State::Closed;
+		}
+
+		// The client is the new connection's now, so nothing is tidied up when this one is removed
+		self.client_id = None;
+		self.take_client_state()
+	}
+
+	// Hands over the client state to be saved once the connection has closed
+	pub fn take_client_state(&mut self) -> ClientState {
+		mem::replace(&mut self.client_state, ClientState::new())
+	}
+
+	// Hands over the actions queued up by the last event
+	pub fn take_actions(&mut self) -> Vec<Action> {
+		mem::replace(&mut self.actions, Vec::new())
+	}
+
+	// A CONNACK which refuses the connection must be followed by closing it
+	fn refuse_connect(&mut self, reason_code: ReasonCode) {
+		self.send_packet(&Packet::ConnectAck {
+			session_present: false,
+			reason_code: reason_code,
+			properties: Vec::new()
+		});
+
+		self.state = State::Closing;
+	}
+
+	fn protocol_error(&mut self) {
+		self.disconnect(ReasonCode::ProtocolError);
+	}
+
+	// MQTT 5 clients are told why the connection is being closed, older versions just see it close
+	fn disconnect(&mut self, reason_code: ReasonCode) {
+		if self.protocol_version == Some(ProtocolVersion::Mqtt5) {
+			self.send_packet(&Packet::Disconnect {
+				reason_code: reason_code,
+				properties: Vec::new()
+			});
+
+			self.state = State::Closing;
+		} else {
+			self.state = State::Closed;
+		}
+	}
+
+	// Packets are sent using the version from CONNECT, or 3.1.1 before there is one.
+	// A packet too big for the encoding closes the connection, since the client would never get it.
+	// One bigger than the client's maximum packet size is dropped. Returns whether the packet was queued.
+	fn send_packet(&mut self, packet: &Packet) -> bool {
+		let version = self.protocol_version.unwrap_or(ProtocolVersion::Mqtt311);
+
+		let mut buf = Vec::new();
+
+		if let Err(e) = encode_packet(packet, version, &mut buf) {
+			println!("Failed to encode {} for {:?} - {}, closing the connection", packet.control_type(), self.token, e);
+			self.state = State::Closed;
+			return false;
+		}
+
+		if self.max_outgoing_packet_size.map_or(false, |max| buf.len() > max) {
+			println!("{} for {:?} is larger than its maximum packet size, dropping it", packet.control_type(), self.token);
+			return false;
+		}
+
+		// Otherwise a client which never reads would have everything sent to it buffered forever
+		if !self.write_queue.push(&buf) {
+			println!("{:?} has fallen too far behind reading, closing the connection", self.token);
+			self.state = State::Closed;
+			return false;
+		}
+
+		true
+	}
+
+	// Writes out as much of the queue as the socket will take, then waits for whatever the session needs next.
+	// If the socket can't be waited on the session is closed, since it would never hear from it again.
+	pub fn flush(&mut self, poll: &mut Poll) -> io::Result<()> {
+		if self.is_closed() {
+			return Ok(());
+		}
+
+		self.write();
+
+		if self.state == State::Closing && self.write_queue.is_empty() {
+			self.state = State::Closed;
+		}
+
+		if self.is_closed() {
+			return Ok(());
+		}
+
+		self.reregister(poll).or_else(|e| {
+			self.state = State::Closed;
+			Err(e)
+		})
+	}
+
+	fn write(&mut self) {
+		if let Err(e) = self.write_queue.write_to(&mut self.socket) {
+			println!("Error calling write in Session - {}", e);
+			self.state = State::Closed;
+		}
 	}
 
 	fn reregister(&mut self, poll: &mut Poll) -> io::Result<()> {
-		let mut interest = Ready::readable();
-		interest.insert(Ready::hup());
+		let mut interest = Ready::hup();
+
+		if self.is_open() {
+			interest.insert(Ready::readable());
+		}
+
+		// Only wait for the socket to become writable while there is something to write,
+		// otherwise we'd be woken up constantly
+		if !self.write_queue.is_empty() {
+			interest.insert(Ready::writable());
+		}
 
-		poll.register(&self.socket, self.token, interest, PollOpt::edge() | PollOpt::oneshot())
+		poll.reregister(&self.socket, self.token, interest, PollOpt::edge() | PollOpt::oneshot())
 		.or_else(|e| {
 			println!("Failed to reregister {:?}, {:?}", self.token, e);
 			Err(e)
 		})
 	}
 
+	fn is_open(&self) -> bool {
+		match self.state {
+			State::Connecting | State::Resuming | State::Connected => true,
+			_ => false
+		}
+	}
+
 	pub fn is_closed(&self) -> bool {
 		match self.state {
 			State::Closed => true,
@@ -99,3 +849,479 @@ impl Session {
 		}
 	}
 }
+
+#[cfg(test)]
+use super::parser::packet_parser;
+#[cfg(test)]
+use super::protocol::{ConnectFlags, ConnectPayload, ConnectVariableHeader};
+#[cfg(test)]
+use super::topic::TopicName;
+#[cfg(test)]
+use nom::IResult;
+#[cfg(test)]
+use std::net;
+
+// A session on one end of a loopback connection. The other end is returned too, so it stays open.
+#[cfg(test)]
+fn test_session() -> (Session, net::TcpStream) {
+	test_connection(1)
+}
+
+#[cfg(test)]
+fn test_connection(connection_id: u64) -> (Session, net::TcpStream) {
+	test_connection_with(connection_id, &Config::default())
+}
+
+#[cfg(test)]
+fn test_connection_with(connection_id: u64, config: &Config) -> (Session, net::TcpStream) {
+	let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	let (socket, _) = listener.accept().unwrap();
+
+	(Session::new(TcpStream::from_stream(socket).unwrap(), Token(1), connection_id, config), client)
+}
+
+#[cfg(test)]
+pub fn connect_packet(version: ProtocolVersion, client_id: &str) -> ConnectPacket {
+	ConnectPacket {
+		variable_header: ConnectVariableHeader {
+			protocol_name: version.protocol_name().into(),
+			protocol_level: version.protocol_level(),
+			connect_flags: ConnectFlags {
+				clean_session: true,
+				will_flag: false,
+				will_qos: QualityOfService::AtMostOnce,
+				will_retain: false,
+				password: false,
+				username: false
+			},
+			keep_alive: 60,
+			properties: Vec::new()
+		},
+		payload: ConnectPayload {
+			client_id: client_id.into(),
+			will_properties: Vec::new(),
+			will_topic: None,
+			will_message: None,
+			username: None,
+			password: None
+		}
+	}
+}
+
+#[cfg(test)]
+pub fn publish(topic_name: &str, qos: QualityOfService, packet_id: Option<u16>) -> PublishPacket {
+	PublishPacket {
+		dup: false,
+		qos: qos,
+		retain: false,
+		topic_name: TopicName::new(topic_name).unwrap(),
+		packet_id: packet_id,
+		properties: Vec::new(),
+		payload: b"hello".to_vec()
+	}
+}
+
+// Handles CONNECT the way MqttHandler does for a client with no saved state
+#[cfg(test)]
+fn connect(session: &mut Session, connect: ConnectPacket) {
+	session.handle_packet(Packet::Connect(connect));
+	session.take_actions();
+	session.resume(None);
+}
+
+// Decodes everything the session has queued to send, emptying the queue
+#[cfg(test)]
+fn sent_packets(session: &mut Session) -> Vec<Packet> {
+	let mut buf = Vec::new();
+	session.write_queue.write_to(&mut buf).unwrap();
+
+	let version = session.protocol_version.unwrap_or(ProtocolVersion::Mqtt311);
+	let mut input = &buf[..];
+	let mut packets = Vec::new();
+
+	while !input.is_empty() {
+		match packet_parser(input, version) {
+			IResult::Done(rest, packet) => {
+				packets.push(packet);
+				input = rest;
+			}
+			e => panic!("{:?}", e)
+		}
+	}
+
+	packets
+}
+
+#[test]
+fn test_publish_reserved_topic() {
+	for version in vec!(ProtocolVersion::Mqtt311, ProtocolVersion::Mqtt5) {
+		let (mut session, _client) = test_session();
+		connect(&mut session, connect_packet(version, "a"));
+		sent_packets(&mut session);
+
+		session.handle_packet(Packet::Publish(publish("$SYS/a", QualityOfService::AtMostOnce, None)));
+		session.handle_packet(Packet::Publish(publish("$SYS/a", QualityOfService::AtLeastOnce, Some(1))));
+		session.handle_packet(Packet::Publish(publish("$SYS/a", QualityOfService::ExactlyOnce, Some(2))));
+
+		// Nothing is routed, but the connection stays open
+		assert_eq!(session.take_actions(), vec!());
+		assert_eq!(session.state, State::Connected);
+
+		// MQTT 5 clients are told the topic name is invalid, earlier versions can't be
+		let reason_code = if version.is_mqtt5() { ReasonCode::TopicNameInvalid } else { ReasonCode::Success };
+
+		assert_eq!(sent_packets(&mut session), vec!(
+			Packet::PublishAck { packet_id: 1, reason_code: reason_code, properties: Vec::new() },
+			Packet::PublishReceived { packet_id: 2, reason_code: reason_code, properties: Vec::new() }
+		));
+	}
+}
+
+#[test]
+fn test_will_reserved_topic() {
+	let connect_with_will = |version| {
+		let mut connect = connect_packet(version, "a");
+		connect.variable_header.connect_flags.will_flag = true;
+		connect.payload.will_topic = Some(TopicName::new("$SYS/will").unwrap());
+		connect.payload.will_message = Some(b"gone".to_vec());
+		connect
+	};
+
+	// MQTT 5 clients are refused
+	let (mut session, _client) = test_session();
+	session.handle_packet(Packet::Connect(connect_with_will(ProtocolVersion::Mqtt5)));
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(session.state, State::Closing);
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::ConnectAck { session_present: false, reason_code: ReasonCode::TopicNameInvalid, properties: Vec::new() }
+	));
+
+	// Earlier versions connect without the will
+	let (mut session, _client) = test_session();
+	connect(&mut session, connect_with_will(ProtocolVersion::Mqtt311));
+	assert_eq!(session.state, State::Connected);
+	assert_eq!(session.take_will(), None);
+}
+
+#[test]
+fn test_deliver_unencodable() {
+	let (mut session, _client) = test_session();
+	connect(&mut session, connect_packet(ProtocolVersion::Mqtt5, "a"));
+	sent_packets(&mut session);
+
+	let mut message = publish("a/b", QualityOfService::AtLeastOnce, Some(1));
+	message.properties.push(Property::UserProperty("k".into(), "v".repeat(70_000)));
+
+	// Only this session is closed, and the message isn't kept to fail again on reconnect
+	session.deliver(&message, QualityOfService::AtLeastOnce, false);
+	assert!(session.is_closed());
+	assert!(session.client_state.inflight.is_empty());
+}
+
+#[test]
+fn test_packet_before_connect() {
+	let (mut session, _client) = test_session();
+
+	// There's no version to reply with, so the connection is just dropped
+	session.handle_packet(Packet::PingRequest);
+	assert_eq!(session.state, State::Closed);
+	assert_eq!(sent_packets(&mut session), vec!());
+}
+
+#[test]
+fn test_second_connect() {
+	for version in vec!(ProtocolVersion::Mqtt311, ProtocolVersion::Mqtt5) {
+		let (mut session, _client) = test_session();
+		connect(&mut session, connect_packet(version, "a"));
+		sent_packets(&mut session);
+
+		session.handle_packet(Packet::Connect(connect_packet(version, "a")));
+		assert_eq!(session.take_actions(), vec!());
+
+		// Only MQTT 5 clients are told why
+		if version.is_mqtt5() {
+			assert_eq!(session.state, State::Closing);
+			assert_eq!(sent_packets(&mut session), vec!(Packet::Disconnect { reason_code: ReasonCode::ProtocolError, properties: Vec::new() }));
+		} else {
+			assert_eq!(session.state, State::Closed);
+			assert_eq!(sent_packets(&mut session), vec!());
+		}
+	}
+}
+
+#[test]
+fn test_connect_refused() {
+	// Unsupported protocol level, which is refused with return code 0x01
+	let (mut session, _client) = test_session();
+	let mut connect = connect_packet(ProtocolVersion::Mqtt311, "a");
+	connect.variable_header.protocol_level = 6;
+
+	session.handle_packet(Packet::Connect(connect));
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(session.state, State::Closing);
+
+	let mut buf = Vec::new();
+	session.write_queue.write_to(&mut buf).unwrap();
+	assert_eq!(buf, vec!(0x20, 0x02, 0x00, 0x01));
+
+	// MQTT 3.1 client ids can't be empty, which is refused with return code 0x02
+	let (mut session, _client) = test_session();
+	session.handle_packet(Packet::Connect(connect_packet(ProtocolVersion::Mqtt31, "")));
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(session.state, State::Closing);
+
+	let mut buf = Vec::new();
+	session.write_queue.write_to(&mut buf).unwrap();
+	assert_eq!(buf, vec!(0x20, 0x02, 0x00, 0x02));
+}
+
+#[test]
+fn test_packets_while_resuming() {
+	let (mut session, _client) = test_session();
+
+	session.handle_packet(Packet::Connect(connect_packet(ProtocolVersion::Mqtt311, "a")));
+	assert_eq!(session.state, State::Resuming);
+	assert_eq!(session.take_actions(), vec!(Action::Connect { client_id: "a".into(), clean_session: true }));
+
+	// Nothing is handled until the handler has restored the session
+	session.handle_packet(Packet::Subscribe {
+		packet_id: 1,
+		properties: Vec::new(),
+		topics: vec!(SubscribeTopic::new(TopicFilter::new("a/+").unwrap(), QualityOfService::AtLeastOnce))
+	});
+	session.handle_packet(Packet::PingRequest);
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(sent_packets(&mut session), vec!());
+
+	session.resume(None);
+	assert_eq!(session.state, State::Connected);
+	assert_eq!(session.take_actions(), vec!(
+		Action::Subscribe(TopicFilter::new("a/+").unwrap(), Subscription::new(QualityOfService::AtLeastOnce)),
+		Action::SendRetained(TopicFilter::new("a/+").unwrap(), QualityOfService::AtLeastOnce)
+	));
+
+	// The CONNACK goes first, then the replies in the order the packets arrived
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::ConnectAck { session_present: false, reason_code: ReasonCode::Success, properties: Vec::new() },
+		Packet::SubscribeAck { packet_id: 1, properties: Vec::new(), return_codes: vec!(SubscribeReturnCode::Success(QualityOfService::AtLeastOnce)) },
+		Packet::PingResponse
+	));
+}
+
+#[test]
+fn test_server_packets_from_client() {
+	let packets = vec!(
+		Packet::ConnectAck { session_present: false, reason_code: ReasonCode::Success, properties: Vec::new() },
+		Packet::SubscribeAck { packet_id: 1, properties: Vec::new(), return_codes: Vec::new() },
+		Packet::UnsubscribeAck { packet_id: 1, properties: Vec::new(), reason_codes: Vec::new() },
+		Packet::PingResponse,
+		Packet::Auth { reason_code: ReasonCode::ContinueAuthentication, properties: Vec::new() }
+	);
+
+	for packet in packets {
+		let (mut session, _client) = test_session();
+		connect(&mut session, connect_packet(ProtocolVersion::Mqtt5, "a"));
+		sent_packets(&mut session);
+
+		session.handle_packet(packet);
+		assert_eq!(session.state, State::Closing);
+		assert_eq!(sent_packets(&mut session), vec!(Packet::Disconnect { reason_code: ReasonCode::ProtocolError, properties: Vec::new() }));
+	}
+}
+
+#[test]
+fn test_assigned_client_id() {
+	// The same token is used for both connections, but the ids are different
+	let (mut first, _client) = test_connection(1);
+	connect(&mut first, connect_packet(ProtocolVersion::Mqtt5, ""));
+	assert_eq!(first.client_id, Some("auto-1".into()));
+	assert_eq!(sent_packets(&mut first), vec!(Packet::ConnectAck {
+		session_present: false,
+		reason_code: ReasonCode::Success,
+		properties: vec!(
+			Property::AssignedClientIdentifier("auto-1".into()),
+			Property::SubscriptionIdentifierAvailable(0),
+			Property::SharedSubscriptionAvailable(0)
+		)
+	}));
+
+	let (mut second, _client) = test_connection(2);
+	connect(&mut second, connect_packet(ProtocolVersion::Mqtt311, ""));
+	assert_eq!(second.client_id, Some("auto-2".into()));
+
+	// Clients can't pick an id which the server could assign
+	let (mut session, _client) = test_connection(3);
+	session.handle_packet(Packet::Connect(connect_packet(ProtocolVersion::Mqtt5, "auto-1")));
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(session.state, State::Closing);
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::ConnectAck { session_present: false, reason_code: ReasonCode::ClientIdentifierNotValid, properties: Vec::new() }
+	));
+}
+
+#[test]
+fn test_unsupported_subscription_features() {
+	let (mut session, _client) = test_session();
+	connect(&mut session, connect_packet(ProtocolVersion::Mqtt5, "a"));
+
+	// MQTT 5 clients are told what isn't supported
+	assert_eq!(sent_packets(&mut session), vec!(Packet::ConnectAck {
+		session_present: false,
+		reason_code: ReasonCode::Success,
+		properties: vec!(Property::SubscriptionIdentifierAvailable(0), Property::SharedSubscriptionAvailable(0))
+	}));
+
+	// Shared subscriptions are refused one filter at a time
+	session.handle_packet(Packet::Subscribe {
+		packet_id: 1,
+		properties: Vec::new(),
+		topics: vec!(
+			SubscribeTopic::new(TopicFilter::new("$share/group/a").unwrap(), QualityOfService::AtLeastOnce),
+			SubscribeTopic::new(TopicFilter::new("a").unwrap(), QualityOfService::AtLeastOnce)
+		)
+	});
+
+	assert_eq!(session.take_actions(), vec!(
+		Action::Subscribe(TopicFilter::new("a").unwrap(), Subscription::new(QualityOfService::AtLeastOnce)),
+		Action::SendRetained(TopicFilter::new("a").unwrap(), QualityOfService::AtLeastOnce)
+	));
+	assert_eq!(sent_packets(&mut session), vec!(Packet::SubscribeAck {
+		packet_id: 1,
+		properties: Vec::new(),
+		return_codes: vec!(
+			SubscribeReturnCode::Failure(ReasonCode::SharedSubscriptionsNotSupported),
+			SubscribeReturnCode::Success(QualityOfService::AtLeastOnce)
+		)
+	}));
+
+	// A subscription identifier closes the connection
+	session.handle_packet(Packet::Subscribe {
+		packet_id: 2,
+		properties: vec!(Property::SubscriptionIdentifier(1)),
+		topics: vec!(SubscribeTopic::new(TopicFilter::new("b").unwrap(), QualityOfService::AtLeastOnce))
+	});
+
+	assert_eq!(session.take_actions(), vec!());
+	assert_eq!(session.state, State::Closing);
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::Disconnect { reason_code: ReasonCode::SubscriptionIdentifiersNotSupported, properties: Vec::new() }
+	));
+}
+
+#[test]
+fn test_subscription_options() {
+	let (mut session, _client) = test_session();
+	connect(&mut session, connect_packet(ProtocolVersion::Mqtt5, "a"));
+
+	session.handle_packet(Packet::Subscribe {
+		packet_id: 1,
+		properties: Vec::new(),
+		topics: vec!(SubscribeTopic {
+			topic_filter: TopicFilter::new("a").unwrap(),
+			qos: QualityOfService::ExactlyOnce,
+			no_local: true,
+			retain_as_published: true,
+			retain_handling: RetainHandling::DoNotSend
+		})
+	});
+
+	// The options go into the subscription tree along with the QoS
+	assert_eq!(session.take_actions(), vec!(Action::Subscribe(TopicFilter::new("a").unwrap(), Subscription {
+		qos: QualityOfService::ExactlyOnce,
+		no_local: true,
+		retain_as_published: true
+	})));
+}
+
+#[test]
+fn test_receive_maximum() {
+	let (mut session, _client) = test_session();
+	let mut connect_with_limit = connect_packet(ProtocolVersion::Mqtt5, "a");
+	connect_with_limit.variable_header.properties = vec!(Property::ReceiveMaximum(1));
+	connect(&mut session, connect_with_limit);
+	sent_packets(&mut session);
+
+	for packet_id in 1..4 {
+		session.deliver(&publish("a", QualityOfService::AtLeastOnce, Some(packet_id)), QualityOfService::AtLeastOnce, false);
+	}
+
+	// QoS 0 messages aren't held back
+	session.deliver(&publish("b", QualityOfService::AtMostOnce, None), QualityOfService::AtMostOnce, false);
+
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(1))),
+		Packet::Publish(publish("b", QualityOfService::AtMostOnce, None))
+	));
+	assert_eq!(session.client_state.queued.len(), 2);
+
+	// Each acknowledgement makes room for the next message
+	session.handle_packet(Packet::PublishAck { packet_id: 1, reason_code: ReasonCode::Success, properties: Vec::new() });
+	assert_eq!(sent_packets(&mut session), vec!(Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(2)))));
+
+	session.handle_packet(Packet::PublishAck { packet_id: 2, reason_code: ReasonCode::Success, properties: Vec::new() });
+	assert_eq!(sent_packets(&mut session), vec!(Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(3)))));
+	assert!(session.client_state.queued.is_empty());
+
+	// A limit of 0 isn't allowed
+	let (mut session, _client) = test_session();
+	let mut connect = connect_packet(ProtocolVersion::Mqtt5, "a");
+	connect.variable_header.properties = vec!(Property::ReceiveMaximum(0));
+
+	session.handle_packet(Packet::Connect(connect));
+	assert_eq!(session.state, State::Closing);
+	assert_eq!(sent_packets(&mut session), vec!(
+		Packet::ConnectAck { session_present: false, reason_code: ReasonCode::ProtocolError, properties: Vec::new() }
+	));
+}
+
+#[test]
+fn test_maximum_packet_size() {
+	let (mut session, _client) = test_session();
+	let mut connect_with_limit = connect_packet(ProtocolVersion::Mqtt5, "a");
+	connect_with_limit.variable_header.properties = vec!(Property::MaximumPacketSize(20));
+	connect(&mut session, connect_with_limit);
+	sent_packets(&mut session);
+
+	let mut large = publish("a", QualityOfService::AtLeastOnce, Some(1));
+	large.payload = vec!(0; 20);
+
+	// The message is dropped as if it had been acknowledged, and the connection carries on
+	session.deliver(&large, QualityOfService::AtLeastOnce, false);
+	assert_eq!(sent_packets(&mut session), vec!());
+	assert!(session.client_state.inflight.is_empty());
+	assert_eq!(session.state, State::Connected);
+
+	session.deliver(&publish("a", QualityOfService::AtLeastOnce, Some(1)), QualityOfService::AtLeastOnce, false);
+	assert_eq!(sent_packets(&mut session), vec!(Packet::Publish(publish("a", QualityOfService::AtLeastOnce, Some(2)))));
+}
+
+#[test]
+fn test_write_queue_full() {
+	let mut config = Config::default();
+	config.max_write_queue_size = 100;
+
+	let (mut session, _client) = test_connection_with(1, &config);
+	connect(&mut session, connect_packet(ProtocolVersion::Mqtt311, "a"));
+
+	// Nothing is flushed, like a client which has stopped reading
+	for _ in 0..10 {
+		session.deliver(&publish("a/b", QualityOfService::AtLeastOnce, Some(1)), QualityOfService::AtLeastOnce, false);
+	}
+
+	assert!(session.is_closed());
+	assert!(session.write_queue.len() <= 100);
+
+	// Only the messages which were queued are waiting to be acknowledged
+	assert_eq!(session.client_state.inflight.len(), sent_packets(&mut session).len() - 1);
+}
+
+#[test]
+fn test_flush_failure() {
+	let mut poll = Poll::new().unwrap();
+	let (mut session, _client) = test_session();
+
+	// The socket was never registered, so waiting on it again fails
+	assert!(session.flush(&mut poll).is_err());
+	assert!(session.is_closed());
+}
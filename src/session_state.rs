@@ -1,5 +1,14 @@
+// Reading and writing happen independently while a session is open
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum State {
-	Reading,
-	Writing,
+	// Open, but the only packet the client may send is CONNECT
+	Connecting,
+	// Open, and CONNECT has been accepted. Packets are held back until the MqttHandler
+	// has restored any saved client state.
+	Resuming,
+	// Open, and CONNECT has been accepted
+	Connected,
+	// Nothing more is read, and the connection is closed once everything queued has been written
+	Closing,
 	Closed
 }
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use protocol::QualityOfService;
+use topic::{TopicFilter, TopicName};
+
+// What a subscriber asked for when it subscribed to a filter
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Subscription {
+	pub qos: QualityOfService,
+	// The subscriber isn't sent the messages it publishes itself
+	pub no_local: bool,
+	// Messages keep their RETAIN flag, rather than having it cleared
+	pub retain_as_published: bool
+}
+
+impl Subscription {
+	pub fn new(qos: QualityOfService) -> Subscription {
+		Subscription {
+			qos: qos,
+			no_local: false,
+			retain_as_published: false
+		}
+	}
+}
+
+// One level of a topic filter. Wildcard levels are stored as children named "+" and "#",
+// which can't clash with a level of a topic name.
+struct Node<S> {
+	children: HashMap<String, Node<S>>,
+	// The subscribers whose filter ends at this level, with what they were granted
+	subscribers: HashMap<S, Subscription>
+}
+
+impl<S: Clone + Eq + Hash> Node<S> {
+	fn new() -> Node<S> {
+		Node {
+			children: HashMap::new(),
+			subscribers: HashMap::new()
+		}
+	}
+
+	fn is_empty(&self) -> bool {
+		self.children.is_empty() && self.subscribers.is_empty()
+	}
+}
+
+// Where a subscriber has more than one matching filter, it gets the message once, at the highest QoS of them,
+// and with RETAIN as published if any of them asked for it. Filters with no_local don't match the publisher's messages.
+fn add_subscribers<S: Clone + Eq + Hash>(matched: &mut HashMap<S, Subscription>, node: &Node<S>, publisher: Option<&S>) {
+	for (subscriber, &subscription) in &node.subscribers {
+		if subscription.no_local && Some(subscriber) == publisher {
+			continue;
+		}
+
+		let granted = matched.entry(subscriber.clone()).or_insert(subscription);
+
+		if subscription.qos > granted.qos {
+			granted.qos = subscription.qos;
+		}
+
+		granted.retain_as_published |= subscription.retain_as_published;
+	}
+}
+
+// Maps topic filters to the subscribers to them, split into a trie of topic levels.
+// Finding the subscribers for a topic only visits the levels of that topic and
+// the wildcards along the way, so it doesn't slow down as more filters are added.
+// The MqttHandler identifies subscribers by client id, so subscriptions can outlive a connection.
+pub struct SubscriptionTree<S> {
+	root: Node<S>,
+	subscription_count: usize
+}
+
+impl<S: Clone + Eq + Hash> SubscriptionTree<S> {
+	pub fn new() -> SubscriptionTree<S> {
+		SubscriptionTree {
+			root: Node::new(),
+			subscription_count: 0
+		}
+	}
+
+	// Subscribing to the same filter again replaces the subscription,
+	// and the previous one is returned
+	pub fn insert(&mut self, filter: &TopicFilter, subscriber: S, subscription: Subscription) -> Option<Subscription> {
+		let mut node = &mut self.root;
+
+		for level in filter.levels() {
+			node = node.children.entry(level.into()).or_insert_with(Node::new);
+		}
+
+		let previous = node.subscribers.insert(subscriber, subscription);
+
+		if previous.is_none() {
+			self.subscription_count += 1;
+		}
+
+		previous
+	}
+
+	// Returns the subscription, or None if there was no such subscription
+	pub fn remove(&mut self, filter: &TopicFilter, subscriber: &S) -> Option<Subscription> {
+		let levels: Vec<&str> = filter.levels().collect();
+
+		// Find the deepest node on the path which has to stay once the subscription is gone.
+		// Everything below it only exists for this filter, and can be dropped with it.
+		// Filters can have tens of thousands of levels, so this avoids recursion.
+		let mut keep_depth = 0;
+
+		let removed = {
+			let mut node = &mut self.root;
+
+			for (depth, level) in levels.iter().enumerate() {
+				if depth > 0 && (!node.subscribers.is_empty() || node.children.len() > 1) {
+					keep_depth = depth;
+				}
+
+				node = match node.children.get_mut(*level) {
+					Some(child) => child,
+					None => return None
+				};
+			}
+
+			match node.subscribers.remove(subscriber) {
+				Some(subscription) => (subscription, node.is_empty()),
+				None => return None
+			}
+		};
+
+		self.subscription_count -= 1;
+
+		let (subscription, prune) = removed;
+
+		if prune {
+			let mut node = &mut self.root;
+
+			for level in &levels[..keep_depth] {
+				node = node.children.get_mut(*level).expect("Subscription tree path disappeared while pruning");
+			}
+
+			node.children.remove(levels[keep_depth]);
+		}
+
+		Some(subscription)
+	}
+
+	// Every subscriber with a filter matching the topic, and how it should receive the message.
+	// The publisher is left out of filters it subscribed to with no_local set.
+	pub fn matches(&self, topic: &TopicName, publisher: Option<&S>) -> HashMap<S, Subscription> {
+		let levels: Vec<&str> = topic.levels().collect();
+		let mut matched = HashMap::new();
+		let mut pending = vec!((&self.root, 0));
+
+		while let Some((node, depth)) = pending.pop() {
+			// Filters starting with a wildcard don't match topics starting with $
+			let wildcards = depth > 0 || !topic.is_system();
+
+			// # matches all of the remaining levels, including none at all,
+			// so "a/#" matches "a" as well as "a/b"
+			if wildcards {
+				if let Some(multi_level) = node.children.get("#") {
+					add_subscribers(&mut matched, multi_level, publisher);
+				}
+			}
+
+			if depth == levels.len() {
+				add_subscribers(&mut matched, node, publisher);
+				continue;
+			}
+
+			if let Some(child) = node.children.get(levels[depth]) {
+				pending.push((child, depth + 1));
+			}
+
+			if wildcards {
+				if let Some(single_level) = node.children.get("+") {
+					pending.push((single_level, depth + 1));
+				}
+			}
+		}
+
+		matched
+	}
+
+	pub fn len(&self) -> usize {
+		self.subscription_count
+	}
+}
+
+#[cfg(test)]
+use mio::Token;
+
+#[cfg(test)]
+fn filter(filter: &str) -> TopicFilter {
+	TopicFilter::new(filter).unwrap()
+}
+
+#[cfg(test)]
+fn matching_tokens(tree: &SubscriptionTree<Token>, topic: &str) -> Vec<usize> {
+	let mut tokens: Vec<usize> = tree.matches(&TopicName::new(topic).unwrap(), None).keys().map(|token| token.0).collect();
+	tokens.sort();
+	tokens
+}
+
+#[test]
+fn test_matches() {
+	let mut tree = SubscriptionTree::new();
+
+	let filters = vec!("a/b", "a/+", "a/#", "#", "+/b", "+", "a/b/c", "/+", "+/+/c", "a//b");
+
+	for (index, f) in filters.iter().enumerate() {
+		tree.insert(&filter(f), Token(index), Subscription::new(QualityOfService::AtMostOnce));
+	}
+
+	assert_eq!(tree.len(), filters.len());
+
+	assert_eq!(matching_tokens(&tree, "a/b"), vec!(0, 1, 2, 3, 4));
+	assert_eq!(matching_tokens(&tree, "a"), vec!(2, 3, 5));
+	assert_eq!(matching_tokens(&tree, "a/c"), vec!(1, 2, 3));
+	assert_eq!(matching_tokens(&tree, "a/b/c"), vec!(2, 3, 6, 8));
+	assert_eq!(matching_tokens(&tree, "b"), vec!(3, 5));
+	assert_eq!(matching_tokens(&tree, "/b"), vec!(3, 4, 7));
+	assert_eq!(matching_tokens(&tree, "a//b"), vec!(2, 3, 9));
+	assert_eq!(matching_tokens(&tree, "a/"), vec!(1, 2, 3));
+}
+
+#[test]
+fn test_system_topics() {
+	let mut tree = SubscriptionTree::new();
+
+	tree.insert(&filter("#"), Token(0), Subscription::new(QualityOfService::AtMostOnce));
+	tree.insert(&filter("+/broker"), Token(1), Subscription::new(QualityOfService::AtMostOnce));
+	tree.insert(&filter("$SYS/#"), Token(2), Subscription::new(QualityOfService::AtMostOnce));
+	tree.insert(&filter("$SYS/+"), Token(3), Subscription::new(QualityOfService::AtMostOnce));
+	tree.insert(&filter("$SYS/broker"), Token(4), Subscription::new(QualityOfService::AtMostOnce));
+
+	assert_eq!(matching_tokens(&tree, "$SYS/broker"), vec!(2, 3, 4));
+	assert_eq!(matching_tokens(&tree, "$SYS"), vec!(2));
+	assert_eq!(matching_tokens(&tree, "SYS/broker"), vec!(0, 1));
+}
+
+#[test]
+fn test_highest_qos_wins() {
+	let mut tree = SubscriptionTree::new();
+	let token = Token(1);
+
+	tree.insert(&filter("a/+"), token, Subscription::new(QualityOfService::AtLeastOnce));
+	tree.insert(&filter("a/#"), token, Subscription::new(QualityOfService::ExactlyOnce));
+	tree.insert(&filter("a/b"), token, Subscription::new(QualityOfService::AtMostOnce));
+
+	let matched = tree.matches(&TopicName::new("a/b").unwrap(), None);
+
+	assert_eq!(matched.len(), 1);
+	assert_eq!(matched[&token].qos, QualityOfService::ExactlyOnce);
+
+	// Subscribing again replaces the QoS
+	assert_eq!(tree.insert(&filter("a/#"), token, Subscription::new(QualityOfService::AtMostOnce)), Some(Subscription::new(QualityOfService::ExactlyOnce)));
+	assert_eq!(tree.matches(&TopicName::new("a/b").unwrap(), None)[&token].qos, QualityOfService::AtLeastOnce);
+	assert_eq!(tree.len(), 3);
+}
+
+#[test]
+fn test_remove() {
+	let mut tree = SubscriptionTree::new();
+
+	tree.insert(&filter("a/b/c/d"), Token(0), Subscription::new(QualityOfService::AtLeastOnce));
+	tree.insert(&filter("a/b/c/d"), Token(1), Subscription::new(QualityOfService::AtLeastOnce));
+	tree.insert(&filter("a/b"), Token(2), Subscription::new(QualityOfService::AtLeastOnce));
+	tree.insert(&filter("a/x/y"), Token(3), Subscription::new(QualityOfService::AtLeastOnce));
+
+	assert_eq!(tree.remove(&filter("a/b/c"), &Token(0)), None);
+	assert_eq!(tree.remove(&filter("a/b/c/d"), &Token(2)), None);
+	assert_eq!(tree.remove(&filter("a/b/c/d"), &Token(0)), Some(Subscription::new(QualityOfService::AtLeastOnce)));
+	assert_eq!(matching_tokens(&tree, "a/b/c/d"), vec!(1));
+
+	// Removing the last subscriber drops the levels which only it used
+	assert_eq!(tree.remove(&filter("a/b/c/d"), &Token(1)), Some(Subscription::new(QualityOfService::AtLeastOnce)));
+	assert!(tree.root.children["a"].children["b"].children.is_empty());
+	assert_eq!(matching_tokens(&tree, "a/b"), vec!(2));
+
+	assert_eq!(tree.remove(&filter("a/x/y"), &Token(3)), Some(Subscription::new(QualityOfService::AtLeastOnce)));
+	assert!(!tree.root.children["a"].children.contains_key("x"));
+
+	assert_eq!(tree.remove(&filter("a/b"), &Token(2)), Some(Subscription::new(QualityOfService::AtLeastOnce)));
+	assert!(tree.root.is_empty());
+	assert_eq!(tree.len(), 0);
+}
+
+#[test]
+fn test_many_filters() {
+	let mut tree = SubscriptionTree::new();
+
+	// One subscription per device, like a fleet of devices would make
+	for device in 0..200_000 {
+		tree.insert(&filter(&format!("devices/{}/commands/#", device)), Token(device), Subscription::new(QualityOfService::AtLeastOnce));
+	}
+
+	tree.insert(&filter("devices/+/status"), Token(200_000), Subscription::new(QualityOfService::AtMostOnce));
+
+	assert_eq!(tree.len(), 200_001);
+	assert_eq!(matching_tokens(&tree, "devices/12345/commands/reboot"), vec!(12345));
+	assert_eq!(matching_tokens(&tree, "devices/12345/status"), vec!(200_000));
+	assert_eq!(matching_tokens(&tree, "devices/unknown/commands"), Vec::<usize>::new());
+}
+
+#[test]
+fn test_no_local() {
+	let mut tree = SubscriptionTree::new();
+	let no_local = Subscription { no_local: true, ..Subscription::new(QualityOfService::AtLeastOnce) };
+
+	tree.insert(&filter("a/b"), Token(1), no_local);
+	tree.insert(&filter("a/b"), Token(2), no_local);
+	tree.insert(&filter("a/+"), Token(3), Subscription::new(QualityOfService::AtMostOnce));
+	tree.insert(&filter("a/#"), Token(3), no_local);
+
+	let topic = TopicName::new("a/b").unwrap();
+
+	// The publisher's no_local filters don't match its own messages
+	let matched = tree.matches(&topic, Some(&Token(1)));
+	assert_eq!(matched.len(), 2);
+	assert!(!matched.contains_key(&Token(1)));
+
+	// But its other matching filters still do
+	let matched = tree.matches(&topic, Some(&Token(3)));
+	assert_eq!(matched.len(), 3);
+	assert_eq!(matched[&Token(3)].qos, QualityOfService::AtMostOnce);
+
+	assert_eq!(tree.matches(&topic, None)[&Token(3)].qos, QualityOfService::AtLeastOnce);
+}
+
+#[test]
+fn test_retain_as_published() {
+	let mut tree = SubscriptionTree::new();
+
+	tree.insert(&filter("a/+"), Token(1), Subscription::new(QualityOfService::AtLeastOnce));
+	tree.insert(&filter("a/#"), Token(1), Subscription { retain_as_published: true, ..Subscription::new(QualityOfService::AtMostOnce) });
+
+	// Any matching filter asking for RETAIN as published is enough
+	let matched = tree.matches(&TopicName::new("a/b").unwrap(), None);
+	assert_eq!(matched[&Token(1)], Subscription { qos: QualityOfService::AtLeastOnce, no_local: false, retain_as_published: true });
+}
@@ -0,0 +1,148 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use mio::Token;
+
+#[derive(Debug, Eq, PartialEq)]
+struct Deadline<K> {
+	at: Instant,
+	key: K
+}
+
+// BinaryHeap is a max-heap, so deadlines are ordered backwards to put the soonest first
+impl<K: Ord> Ord for Deadline<K> {
+	fn cmp(&self, other: &Deadline<K>) -> Ordering {
+		other.at.cmp(&self.at).then(other.key.cmp(&self.key))
+	}
+}
+
+impl<K: Ord> PartialOrd for Deadline<K> {
+	fn partial_cmp(&self, other: &Deadline<K>) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+// Deadlines used to work out how long to wait in Poll::poll. The key says what each one is for, and
+// there's at most one deadline per key; scheduling another replaces it. Replaced and cancelled
+// deadlines stay in the heap until they reach the front, where they're dropped.
+pub struct Timer<K> {
+	deadlines: BinaryHeap<Deadline<K>>,
+	// The current deadline for each key which has one
+	current: HashMap<K, Instant>
+}
+
+impl<K: Clone + Eq + Hash + Ord> Timer<K> {
+	pub fn new() -> Timer<K> {
+		Timer {
+			deadlines: BinaryHeap::new(),
+			current: HashMap::new()
+		}
+	}
+
+	pub fn schedule(&mut self, key: K, at: Instant) {
+		self.current.insert(key.clone(), at);
+		self.deadlines.push(Deadline { at: at, key: key });
+	}
+
+	pub fn cancel(&mut self, key: &K) {
+		self.current.remove(key);
+	}
+
+	// How long until the soonest deadline, or None if there aren't any
+	pub fn timeout(&mut self, now: Instant) -> Option<Duration> {
+		self.drop_stale();
+
+		self.deadlines.peek().map(|deadline| {
+			if deadline.at > now {
+				deadline.at - now
+			} else {
+				Duration::from_millis(0)
+			}
+		})
+	}
+
+	// Removes and returns the key of every deadline which has passed
+	pub fn expired(&mut self, now: Instant) -> Vec<K> {
+		let mut expired = Vec::new();
+
+		while self.deadlines.peek().map_or(false, |deadline| deadline.at <= now) {
+			let deadline = self.deadlines.pop().unwrap();
+
+			if self.current.get(&deadline.key) == Some(&deadline.at) {
+				self.current.remove(&deadline.key);
+				expired.push(deadline.key);
+			}
+		}
+
+		expired
+	}
+
+	// How many keys have a deadline
+	pub fn len(&self) -> usize {
+		self.current.len()
+	}
+
+	fn drop_stale(&mut self) {
+		while self.deadlines.peek().map_or(false, |deadline| self.current.get(&deadline.key) != Some(&deadline.at)) {
+			self.deadlines.pop();
+		}
+	}
+}
+
+#[test]
+fn test_timeout() {
+	let now = Instant::now();
+	let mut timer = Timer::new();
+
+	assert_eq!(timer.timeout(now), None);
+
+	timer.schedule((Token(1), 1), now + Duration::from_secs(30));
+	timer.schedule((Token(2), 2), now + Duration::from_secs(10));
+	timer.schedule((Token(3), 3), now + Duration::from_secs(20));
+
+	assert_eq!(timer.timeout(now), Some(Duration::from_secs(10)));
+	assert_eq!(timer.timeout(now + Duration::from_secs(15)), Some(Duration::from_millis(0)));
+}
+
+#[test]
+fn test_expired() {
+	let now = Instant::now();
+	let mut timer = Timer::new();
+
+	timer.schedule((Token(1), 1), now + Duration::from_secs(30));
+	timer.schedule((Token(2), 2), now + Duration::from_secs(10));
+	timer.schedule((Token(3), 3), now + Duration::from_secs(20));
+
+	assert_eq!(timer.expired(now), vec!());
+	assert_eq!(timer.expired(now + Duration::from_secs(20)), vec!((Token(2), 2), (Token(3), 3)));
+	assert_eq!(timer.len(), 1);
+	assert_eq!(timer.timeout(now), Some(Duration::from_secs(30)));
+
+	assert_eq!(timer.expired(now + Duration::from_secs(60)), vec!((Token(1), 1)));
+	assert_eq!(timer.timeout(now), None);
+}
+
+#[test]
+fn test_replace_and_cancel() {
+	let now = Instant::now();
+	let mut timer = Timer::new();
+
+	// A later deadline for the same key replaces the earlier one
+	timer.schedule((Token(1), 1), now + Duration::from_secs(10));
+	timer.schedule((Token(1), 1), now + Duration::from_secs(30));
+	assert_eq!(timer.len(), 1);
+	assert_eq!(timer.timeout(now), Some(Duration::from_secs(30)));
+	assert_eq!(timer.expired(now + Duration::from_secs(20)), vec!());
+
+	// A key with the same token and a different connection id has a deadline of its own
+	timer.cancel(&(Token(1), 1));
+	timer.schedule((Token(1), 2), now + Duration::from_secs(40));
+	assert_eq!(timer.len(), 1);
+	assert_eq!(timer.expired(now + Duration::from_secs(60)), vec!((Token(1), 2)));
+
+	assert_eq!(timer.timeout(now), None);
+	assert_eq!(timer.len(), 0);
+}
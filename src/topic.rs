@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::fmt;
+use std::str::Split;
+
+// Topics are length prefixed with two bytes on the wire
+pub const MAX_TOPIC_LENGTH: usize = 65535;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TopicError {
+	Empty,
+	TooLong,
+	NullCharacter,
+	// Topic names can't contain + or #
+	WildcardInTopicName,
+	// + has to take up a whole level
+	MisplacedSingleLevelWildcard,
+	// # has to take up a whole level, and be the last one
+	MisplacedMultiLevelWildcard
+}
+
+impl fmt::Display for TopicError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.description())
+	}
+}
+
+impl Error for TopicError {
+	fn description(&self) -> &str {
+		match *self {
+			TopicError::Empty => "topic is empty",
+			TopicError::TooLong => "topic is longer than 65535 bytes",
+			TopicError::NullCharacter => "topic contains U+0000",
+			TopicError::WildcardInTopicName => "topic name contains a wildcard",
+			TopicError::MisplacedSingleLevelWildcard => "single level wildcard doesn't take up a whole level",
+			TopicError::MisplacedMultiLevelWildcard => "multi level wildcard isn't the whole of the last level"
+		}
+	}
+}
+
+// The rules which apply to both names and filters
+fn validate_topic(topic: &str) -> Result<(), TopicError> {
+	if topic.is_empty() {
+		Err(TopicError::Empty)
+	} else if topic.len() > MAX_TOPIC_LENGTH {
+		Err(TopicError::TooLong)
+	} else if topic.contains('\u{0000}') {
+		Err(TopicError::NullCharacter)
+	} else {
+		Ok(())
+	}
+}
+
+// The topic a message is published to, which can't contain wildcards
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TopicName(String);
+
+impl TopicName {
+	pub fn new<S: Into<String>>(name: S) -> Result<TopicName, TopicError> {
+		let name = name.into();
+
+		try!(validate_topic(&name));
+
+		if name.contains(|c| c == '+' || c == '#') {
+			return Err(TopicError::WildcardInTopicName);
+		}
+
+		Ok(TopicName(name))
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	// The parts of the topic between the / separators, which can be empty
+	pub fn levels(&self) -> Split<char> {
+		self.0.split('/')
+	}
+
+	pub fn is_system(&self) -> bool {
+		self.0.starts_with('$')
+	}
+}
+
+impl fmt::Display for TopicName {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+// A pattern of topic names to subscribe to, where + matches any one level
+// and # matches any number of levels at the end
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TopicFilter(String);
+
+impl TopicFilter {
+	pub fn new<S: Into<String>>(filter: S) -> Result<TopicFilter, TopicError> {
+		let filter = filter.into();
+
+		try!(validate_topic(&filter));
+
+		let level_count = filter.split('/').count();
+
+		for (index, level) in filter.split('/').enumerate() {
+			if level.contains('+') && level != "+" {
+				return Err(TopicError::MisplacedSingleLevelWildcard);
+			}
+
+			if level.contains('#') && (level != "#" || index != level_count - 1) {
+				return Err(TopicError::MisplacedMultiLevelWildcard);
+			}
+		}
+
+		Ok(TopicFilter(filter))
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn levels(&self) -> Split<char> {
+		self.0.split('/')
+	}
+
+	// Wildcards at the start of a filter don't match topics starting with $,
+	// so these have to be subscribed to explicitly
+	pub fn is_system(&self) -> bool {
+		self.0.starts_with('$')
+	}
+
+	// MQTT 5 shared subscriptions, which are written $share/{group}/{filter}
+	pub fn is_shared(&self) -> bool {
+		self.0.starts_with("$share/")
+	}
+
+	pub fn has_wildcards(&self) -> bool {
+		self.0.contains(|c| c == '+' || c == '#')
+	}
+
+	// Checks a single topic against the filter. The SubscriptionTree does the same for many filters at once.
+	pub fn matches(&self, topic: &TopicName) -> bool {
+		if topic.is_system() && !self.is_system() {
+			return false;
+		}
+
+		let mut filter_levels = self.levels();
+		let mut topic_levels = topic.levels();
+
+		loop {
+			match (filter_levels.next(), topic_levels.next()) {
+				// # also matches the level above it
+				(Some("#"), _) => return true,
+				(Some("+"), Some(_)) => {}
+				(Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+				(None, None) => return true,
+				_ => return false
+			}
+		}
+	}
+}
+
+impl fmt::Display for TopicFilter {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[test]
+fn test_topic_name() {
+	for name in vec!("a", "a/b", "/", "a//b", "/a/", " ", "sport/tennis/player1", "$SYS/broker", "ü") {
+		assert_eq!(TopicName::new(name).unwrap().as_str(), name);
+	}
+
+	assert_eq!(TopicName::new(""), Err(TopicError::Empty));
+	assert_eq!(TopicName::new("a\u{0000}b"), Err(TopicError::NullCharacter));
+	assert_eq!(TopicName::new("a/+"), Err(TopicError::WildcardInTopicName));
+	assert_eq!(TopicName::new("a/#"), Err(TopicError::WildcardInTopicName));
+	assert_eq!(TopicName::new("a+b"), Err(TopicError::WildcardInTopicName));
+
+	assert_eq!(TopicName::new(vec!('a'; MAX_TOPIC_LENGTH).into_iter().collect::<String>()).is_ok(), true);
+	assert_eq!(TopicName::new(vec!('a'; MAX_TOPIC_LENGTH + 1).into_iter().collect::<String>()), Err(TopicError::TooLong));
+
+	assert!(TopicName::new("$SYS/broker").unwrap().is_system());
+	assert!(!TopicName::new("a/$").unwrap().is_system());
+
+	assert_eq!(TopicName::new("/a//b").unwrap().levels().collect::<Vec<_>>(), vec!("", "a", "", "b"));
+}
+
+#[test]
+fn test_topic_filter() {
+	for filter in vec!("a", "a/b", "#", "+", "/", "+/+", "/+", "a/#", "a/+/b", "+/b/#", "$SYS/#", "a//#") {
+		assert_eq!(TopicFilter::new(filter).unwrap().as_str(), filter);
+	}
+
+	assert_eq!(TopicFilter::new(""), Err(TopicError::Empty));
+	assert_eq!(TopicFilter::new("a/\u{0000}"), Err(TopicError::NullCharacter));
+	assert_eq!(TopicFilter::new("a+"), Err(TopicError::MisplacedSingleLevelWildcard));
+	assert_eq!(TopicFilter::new("a/+b/c"), Err(TopicError::MisplacedSingleLevelWildcard));
+	assert_eq!(TopicFilter::new("a#"), Err(TopicError::MisplacedMultiLevelWildcard));
+	assert_eq!(TopicFilter::new("a/#/b"), Err(TopicError::MisplacedMultiLevelWildcard));
+	assert_eq!(TopicFilter::new("#/"), Err(TopicError::MisplacedMultiLevelWildcard));
+	assert_eq!(TopicFilter::new("a/##"), Err(TopicError::MisplacedMultiLevelWildcard));
+
+	assert!(TopicFilter::new("$SYS/#").unwrap().is_system());
+	assert!(!TopicFilter::new("+/#").unwrap().is_system());
+	assert!(TopicFilter::new("a/+").unwrap().has_wildcards());
+	assert!(!TopicFilter::new("a/b").unwrap().has_wildcards());
+}
+
+#[test]
+fn test_topic_filter_matches() {
+	let cases = vec!(
+		("a/b", "a/b", true),
+		("a/b", "a/c", false),
+		("a/b", "a/b/c", false),
+		("a/+", "a/b", true),
+		("a/+", "a", false),
+		("a/+", "a/", true),
+		("+/+", "/a", true),
+		("a/#", "a", true),
+		("a/#", "a/b/c", true),
+		("a/#", "b", false),
+		("#", "a/b", true),
+		("#", "$SYS/broker", false),
+		("+/broker", "$SYS/broker", false),
+		("$SYS/#", "$SYS/broker", true),
+		("$SYS/+", "$SYS/broker", true)
+	);
+
+	for (filter, topic, expected) in cases {
+		assert_eq!(TopicFilter::new(filter).unwrap().matches(&TopicName::new(topic).unwrap()), expected, "{} {}", filter, topic);
+	}
+}
@@ -0,0 +1,166 @@
+use std::io;
+use std::io::{ErrorKind, Write};
+
+// Bytes waiting to be written to a socket which might not take them all at once
+pub struct WriteQueue {
+	buf: Vec<u8>,
+	// The most bytes which can be waiting at once
+	max_size: usize
+}
+
+impl WriteQueue {
+	pub fn new(max_size: usize) -> WriteQueue {
+		WriteQueue {
+			buf: Vec::new(),
+			max_size: max_size
+		}
+	}
+
+	// Returns false, and queues nothing, if the bytes would take the queue over its maximum size
+	pub fn push(&mut self, bytes: &[u8]) -> bool {
+		if self.buf.len() + bytes.len() > self.max_size {
+			return false;
+		}
+
+		self.buf.extend_from_slice(bytes);
+		true
+	}
+
+	pub fn len(&self) -> usize {
+		self.buf.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buf.is_empty()
+	}
+
+	// Writes as much as the writer will take without blocking. Anything left over stays queued
+	// until the next call, which should be made once the writer is writable again.
+	pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+		let mut written = 0;
+		let mut result = Ok(());
+
+		while written < self.buf.len() {
+			match writer.write(&self.buf[written..]) {
+				Ok(0) => {
+					result = Err(io::Error::new(ErrorKind::WriteZero, "failed to write queued bytes"));
+					break;
+				}
+				Ok(n) => written += n,
+				Err(e) => {
+					match e.kind() {
+						ErrorKind::WouldBlock => break,
+						ErrorKind::Interrupted => {}
+						_ => {
+							result = Err(e);
+							break;
+						}
+					}
+				}
+			}
+		}
+
+		self.buf.drain(..written);
+		result
+	}
+}
+
+#[cfg(test)]
+struct ChunkedWriter {
+	written: Vec<u8>,
+	// The most bytes accepted by a single call to write
+	chunk_size: usize,
+	// How many more bytes can be written before it would block
+	space: usize
+}
+
+#[cfg(test)]
+impl Write for ChunkedWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.space == 0 {
+			return Err(io::Error::new(ErrorKind::WouldBlock, "would block"));
+		}
+
+		let n = *[buf.len(), self.chunk_size, self.space].iter().min().unwrap();
+
+		self.written.extend_from_slice(&buf[..n]);
+		self.space -= n;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn test_partial_writes() {
+	let mut queue = WriteQueue::new(100);
+	let mut writer = ChunkedWriter { written: Vec::new(), chunk_size: 3, space: 100 };
+
+	assert!(queue.push(&[1, 2, 3, 4]));
+	queue.push(&[5, 6, 7, 8, 9, 10]);
+	assert_eq!(queue.len(), 10);
+
+	queue.write_to(&mut writer).unwrap();
+	assert!(queue.is_empty());
+	assert_eq!(writer.written, vec!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10));
+}
+
+#[test]
+fn test_would_block() {
+	let mut queue = WriteQueue::new(100);
+	let mut writer = ChunkedWriter { written: Vec::new(), chunk_size: 2, space: 5 };
+
+	queue.push(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+	// The writer stops taking bytes part way through, so the rest stays queued
+	queue.write_to(&mut writer).unwrap();
+	assert_eq!(queue.len(), 3);
+	assert_eq!(writer.written, vec!(1, 2, 3, 4, 5));
+
+	// Bytes queued while blocked go out after the ones already waiting
+	queue.push(&[9]);
+	writer.space = 100;
+
+	queue.write_to(&mut writer).unwrap();
+	assert!(queue.is_empty());
+	assert_eq!(writer.written, vec!(1, 2, 3, 4, 5, 6, 7, 8, 9));
+}
+
+#[test]
+fn test_max_size() {
+	let mut queue = WriteQueue::new(5);
+	let mut writer = ChunkedWriter { written: Vec::new(), chunk_size: 2, space: 2 };
+
+	assert!(queue.push(&[1, 2, 3]));
+	assert!(!queue.push(&[4, 5, 6]));
+	assert!(queue.push(&[4, 5]));
+	assert_eq!(queue.len(), 5);
+
+	// Writing makes room again
+	queue.write_to(&mut writer).unwrap();
+	assert!(queue.push(&[6, 7]));
+	assert_eq!(queue.len(), 5);
+}
+
+#[test]
+fn test_write_error() {
+	struct BrokenWriter;
+
+	impl Write for BrokenWriter {
+		fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+			Err(io::Error::new(ErrorKind::BrokenPipe, "broken pipe"))
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	let mut queue = WriteQueue::new(100);
+	queue.push(&[1, 2, 3]);
+
+	assert_eq!(queue.write_to(&mut BrokenWriter).unwrap_err().kind(), ErrorKind::BrokenPipe);
+	assert_eq!(queue.len(), 3);
+}